@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tracing_stable_trace_id_example::trace::parse_trace_header;
+
+fuzz_target!(|input: (&str, &str)| {
+    let (name, value) = input;
+    let _ = parse_trace_header(name, value);
+});
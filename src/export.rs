@@ -0,0 +1,696 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use futures_util::future::BoxFuture;
+use opentelemetry::{
+    sdk::{
+        export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::{EvictedHashMap, Span, SpanProcessor},
+    },
+    trace::{TraceError, TraceId, TraceResult},
+    Context, Key, KeyValue,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportErrorKind {
+    /// The batch will never be accepted (e.g. a malformed span), retrying is pointless.
+    Fatal,
+    /// The collector is temporarily unavailable, the export should be retried.
+    Retryable,
+}
+
+// Classify export errors by the HTTP or gRPC status the collector answered with. 4xx means the
+// request itself is bad, except for timeouts and throttling which are worth another try. Of the
+// gRPC codes, the ones the OTLP specification lists as retryable are, the others are fatal.
+// Everything else (5xx, timeouts, connection errors) is treated as transient.
+pub fn classify_export_error(err: &TraceError) -> ExportErrorKind {
+    if let TraceError::ExportTimedOut(_) = err {
+        return ExportErrorKind::Retryable;
+    }
+
+    let message = err.to_string();
+    if let Some(code) = grpc_status(&message) {
+        return match code {
+            GrpcCode::Cancelled
+            | GrpcCode::DeadlineExceeded
+            | GrpcCode::ResourceExhausted
+            | GrpcCode::Aborted
+            | GrpcCode::OutOfRange
+            | GrpcCode::Unavailable
+            | GrpcCode::DataLoss => ExportErrorKind::Retryable,
+            _ => ExportErrorKind::Fatal,
+        };
+    }
+
+    match http_status(&message) {
+        Some(408 | 429) => ExportErrorKind::Retryable,
+        Some(400..=499) => ExportErrorKind::Fatal,
+        _ => ExportErrorKind::Retryable,
+    }
+}
+
+// opentelemetry-http reports unsuccessful responses as "request failed with status {status}"
+fn http_status(message: &str) -> Option<u16> {
+    let (_, status) = message.split_once("request failed with status ")?;
+    status.get(..3)?.parse().ok()
+}
+
+// The gRPC status codes an export can fail with, `tonic::Code` without depending on tonic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GrpcCode {
+    Cancelled,
+    Unknown,
+    InvalidArgument,
+    DeadlineExceeded,
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    ResourceExhausted,
+    FailedPrecondition,
+    Aborted,
+    OutOfRange,
+    Unimplemented,
+    Internal,
+    Unavailable,
+    DataLoss,
+    Unauthenticated,
+}
+
+// opentelemetry-otlp reports unsuccessful gRPC responses as "the grpc server returns error
+// ({code}): {message}", with the description `tonic::Code` displays as
+fn grpc_status(message: &str) -> Option<GrpcCode> {
+    const DESCRIPTIONS: &[(&str, GrpcCode)] = &[
+        ("The operation was cancelled", GrpcCode::Cancelled),
+        ("Unknown error", GrpcCode::Unknown),
+        (
+            "Client specified an invalid argument",
+            GrpcCode::InvalidArgument,
+        ),
+        (
+            "Deadline expired before operation could complete",
+            GrpcCode::DeadlineExceeded,
+        ),
+        ("Some requested entity was not found", GrpcCode::NotFound),
+        (
+            "Some entity that we attempted to create already exists",
+            GrpcCode::AlreadyExists,
+        ),
+        (
+            "The caller does not have permission to execute the specified operation",
+            GrpcCode::PermissionDenied,
+        ),
+        (
+            "Some resource has been exhausted",
+            GrpcCode::ResourceExhausted,
+        ),
+        (
+            "The system is not in a state required for the operation's execution",
+            GrpcCode::FailedPrecondition,
+        ),
+        ("The operation was aborted", GrpcCode::Aborted),
+        (
+            "Operation was attempted past the valid range",
+            GrpcCode::OutOfRange,
+        ),
+        (
+            "Operation is not implemented or not supported",
+            GrpcCode::Unimplemented,
+        ),
+        ("Internal error", GrpcCode::Internal),
+        (
+            "The service is currently unavailable",
+            GrpcCode::Unavailable,
+        ),
+        ("Unrecoverable data loss or corruption", GrpcCode::DataLoss),
+        (
+            "The request does not have valid authentication credentials",
+            GrpcCode::Unauthenticated,
+        ),
+    ];
+
+    let (_, code) = message.split_once("the grpc server returns error (")?;
+    let (description, _) = code.split_once(')')?;
+    DESCRIPTIONS
+        .iter()
+        .find(|(known, _)| *known == description)
+        .map(|(_, code)| *code)
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, attempt: usize) -> Duration {
+        let factor = 2u32.saturating_pow(attempt as u32);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+// Wraps a `SpanExporter` and retries failed exports which are classified as retryable.
+// Fatal errors are returned right away, so the span processor reports them and drops the batch.
+pub struct RetryingSpanExporter<E> {
+    inner: Arc<Mutex<E>>,
+    policy: RetryPolicy,
+    classify: fn(&TraceError) -> ExportErrorKind,
+}
+
+impl<E: SpanExporter> RetryingSpanExporter<E> {
+    pub fn new(inner: E, policy: RetryPolicy) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            policy,
+            classify: classify_export_error,
+        }
+    }
+
+    pub fn with_classifier(mut self, classify: fn(&TraceError) -> ExportErrorKind) -> Self {
+        self.classify = classify;
+        self
+    }
+}
+
+impl<E: fmt::Debug> fmt::Debug for RetryingSpanExporter<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryingSpanExporter")
+            .field("inner", &self.inner)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl<E: SpanExporter + 'static> SpanExporter for RetryingSpanExporter<E> {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+        let classify = self.classify;
+
+        Box::pin(async move {
+            let mut attempt = 0;
+            loop {
+                // the lock is only held to create the export future, never across an await
+                let export = inner
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .export(batch.clone());
+
+                match export.await {
+                    Ok(()) => return Ok(()),
+                    Err(err)
+                        if attempt < policy.max_retries
+                            && classify(&err) == ExportErrorKind::Retryable =>
+                    {
+                        tokio::time::sleep(policy.backoff(attempt)).await;
+                        attempt += 1;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        })
+    }
+
+    fn shutdown(&mut self) {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .shutdown();
+    }
+
+    fn force_flush(&mut self) -> BoxFuture<'static, ExportResult> {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .force_flush()
+    }
+}
+
+// Wraps a `SpanExporter` and strips the attributes of the spans of a `1 - ratio` fraction of
+// traces down to the `kept` ones, to cut costs while still exporting every span. The decision
+// is derived from the trace id (like `TraceIdRatioBased` sampling), so all spans of a trace
+// get the same treatment.
+#[derive(Debug)]
+pub struct AttributeSamplingExporter<E> {
+    inner: E,
+    ratio: f64,
+    kept: Vec<Key>,
+}
+
+impl<E: SpanExporter> AttributeSamplingExporter<E> {
+    pub fn new(inner: E, ratio: f64) -> Self {
+        Self {
+            inner,
+            ratio,
+            kept: Vec::new(),
+        }
+    }
+
+    /// Attributes which are kept on the spans of traces recorded with the minimal set.
+    pub fn keep_attributes(mut self, kept: impl IntoIterator<Item = impl Into<Key>>) -> Self {
+        self.kept = kept.into_iter().map(Into::into).collect();
+        self
+    }
+
+    fn records_all_attributes(&self, trace_id: TraceId) -> bool {
+        if self.ratio >= 1.0 {
+            return true;
+        }
+        // same computation as the sdk's `TraceIdRatioBased` sampler
+        let prob_upper_bound = (self.ratio.max(0.0) * (1u64 << 63) as f64) as u64;
+        let trace_id = u128::from_be_bytes(trace_id.to_bytes());
+        ((trace_id as u64) >> 1) < prob_upper_bound
+    }
+
+    fn minimal_attributes(&self, attributes: &EvictedHashMap) -> EvictedHashMap {
+        let mut minimal = EvictedHashMap::new(self.kept.len() as u32, self.kept.len());
+        for key in &self.kept {
+            if let Some(value) = attributes.get(key) {
+                minimal.insert(KeyValue::new(key.clone(), value.clone()));
+            }
+        }
+        minimal
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for AttributeSamplingExporter<E> {
+    fn export(&mut self, mut batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        for span in &mut batch {
+            if !self.records_all_attributes(span.span_context.trace_id()) {
+                span.attributes = self.minimal_attributes(&span.attributes);
+            }
+        }
+        self.inner.export(batch)
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown();
+    }
+
+    fn force_flush(&mut self) -> BoxFuture<'static, ExportResult> {
+        self.inner.force_flush()
+    }
+}
+
+// Estimates how many spans wait in a `BatchSpanProcessor`'s queue, which the sdk doesn't expose.
+// Spans are counted when they're handed to the processor (`processor`) and subtracted again
+// once a batch reaches the exporter (`exporter`). The processor drops spans while its queue is
+// full, so the estimate is capped at the configured `max_queue_size`.
+#[derive(Debug, Clone)]
+pub struct QueueDepth {
+    depth: Arc<AtomicUsize>,
+    max_queue_size: usize,
+}
+
+impl QueueDepth {
+    pub fn new(max_queue_size: usize) -> Self {
+        Self {
+            depth: Default::default(),
+            max_queue_size,
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Wrap the batch span processor, to count the spans ending up in its queue.
+    pub fn processor<P: SpanProcessor>(&self, inner: P) -> QueueDepthProcessor<P> {
+        QueueDepthProcessor {
+            inner,
+            depth: self.clone(),
+        }
+    }
+
+    /// Wrap the exporter of the batch span processor, to count the spans leaving its queue.
+    pub fn exporter<E: SpanExporter>(&self, inner: E) -> QueueDepthExporter<E> {
+        QueueDepthExporter {
+            inner,
+            depth: self.clone(),
+        }
+    }
+
+    fn add(&self) {
+        let max_queue_size = self.max_queue_size;
+        let _ = self
+            .depth
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                Some((depth + 1).min(max_queue_size))
+            });
+    }
+
+    fn sub(&self, exported: usize) {
+        let _ = self
+            .depth
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                Some(depth.saturating_sub(exported))
+            });
+    }
+}
+
+#[derive(Debug)]
+pub struct QueueDepthProcessor<P> {
+    inner: P,
+    depth: QueueDepth,
+}
+
+impl<P: SpanProcessor> SpanProcessor for QueueDepthProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        // unsampled spans are discarded by the batch span processor right away
+        if span.span_context.is_sampled() {
+            self.depth.add();
+        }
+        self.inner.on_end(span);
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        self.inner.force_flush()
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        self.inner.shutdown()
+    }
+}
+
+#[derive(Debug)]
+pub struct QueueDepthExporter<E> {
+    inner: E,
+    depth: QueueDepth,
+}
+
+impl<E: SpanExporter> SpanExporter for QueueDepthExporter<E> {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        self.depth.sub(batch.len());
+        self.inner.export(batch)
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown();
+    }
+
+    fn force_flush(&mut self) -> BoxFuture<'static, ExportResult> {
+        self.inner.force_flush()
+    }
+}
+
+/// What to do with an ended span while `InFlightLimit` is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// Discard the span, like the batch span processor does once its queue is full.
+    #[default]
+    Drop,
+    /// Block the thread ending the span until an export frees up space. This deadlocks if the
+    /// export can't make progress while the thread is blocked, e.g. with a current thread
+    /// runtime driving the batch span processor, or when the exporter itself creates spans.
+    Block,
+}
+
+// Limits the number of spans handed to a `BatchSpanProcessor` but not yet passed to its exporter,
+// applying `policy` once `max_in_flight` is reached. Keep the limit below the processor's
+// `max_queue_size`, so the processor itself never has to drop spans.
+#[derive(Debug, Clone)]
+pub struct InFlightLimit {
+    in_flight: Arc<(Mutex<usize>, Condvar)>,
+    max_in_flight: usize,
+    policy: QueueFullPolicy,
+}
+
+impl InFlightLimit {
+    pub fn new(max_in_flight: usize, policy: QueueFullPolicy) -> Self {
+        Self {
+            in_flight: Default::default(),
+            max_in_flight: max_in_flight.max(1),
+            policy,
+        }
+    }
+
+    /// Wrap the batch span processor, to apply the limit to the spans ending up in its queue.
+    pub fn processor<P: SpanProcessor>(&self, inner: P) -> InFlightLimitProcessor<P> {
+        InFlightLimitProcessor {
+            inner,
+            limit: self.clone(),
+        }
+    }
+
+    /// Wrap the exporter of the batch span processor, to free up space for the exported spans.
+    pub fn exporter<E: SpanExporter>(&self, inner: E) -> InFlightLimitExporter<E> {
+        InFlightLimitExporter {
+            inner,
+            limit: self.clone(),
+        }
+    }
+
+    // Whether the span may be passed on to the processor
+    fn acquire(&self) -> bool {
+        let (in_flight, space_freed) = &*self.in_flight;
+        let mut in_flight = in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+
+        while *in_flight >= self.max_in_flight {
+            match self.policy {
+                QueueFullPolicy::Drop => return false,
+                QueueFullPolicy::Block => {
+                    in_flight = space_freed
+                        .wait(in_flight)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            }
+        }
+        *in_flight += 1;
+        true
+    }
+
+    fn release(&self, exported: usize) {
+        let (in_flight, space_freed) = &*self.in_flight;
+        let mut in_flight = in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+        *in_flight = in_flight.saturating_sub(exported);
+        space_freed.notify_all();
+    }
+}
+
+#[derive(Debug)]
+pub struct InFlightLimitProcessor<P> {
+    inner: P,
+    limit: InFlightLimit,
+}
+
+impl<P: SpanProcessor> SpanProcessor for InFlightLimitProcessor<P> {
+    fn on_start(&self, span: &mut Span, cx: &Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, span: SpanData) {
+        // unsampled spans are discarded by the batch span processor right away
+        if !span.span_context.is_sampled() || self.limit.acquire() {
+            self.inner.on_end(span);
+        }
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        self.inner.force_flush()
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        self.inner.shutdown()
+    }
+}
+
+#[derive(Debug)]
+pub struct InFlightLimitExporter<E> {
+    inner: E,
+    limit: InFlightLimit,
+}
+
+impl<E: SpanExporter> SpanExporter for InFlightLimitExporter<E> {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        self.limit.release(batch.len());
+        self.inner.export(batch)
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown();
+    }
+
+    fn force_flush(&mut self) -> BoxFuture<'static, ExportResult> {
+        self.inner.force_flush()
+    }
+}
+
+/// Called with every batch right before it's exported.
+pub type ExportHook = Arc<dyn Fn(&[SpanData]) + Send + Sync>;
+
+// Wraps a `SpanExporter` and passes each batch to `on_export` before exporting it, to tap the
+// export stream (e.g. counting spans per service) without writing a full exporter
+pub struct HookedSpanExporter<E> {
+    inner: E,
+    on_export: ExportHook,
+}
+
+impl<E: SpanExporter> HookedSpanExporter<E> {
+    pub fn new(inner: E, on_export: ExportHook) -> Self {
+        Self { inner, on_export }
+    }
+}
+
+impl<E: fmt::Debug> fmt::Debug for HookedSpanExporter<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HookedSpanExporter")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for HookedSpanExporter<E> {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        (self.on_export)(&batch);
+        self.inner.export(batch)
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown();
+    }
+
+    fn force_flush(&mut self) -> BoxFuture<'static, ExportResult> {
+        self.inner.force_flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grpc_error(description: &str) -> TraceError {
+        format!("the grpc server returns error ({description}): , detailed error message: no")
+            .into()
+    }
+
+    #[test]
+    fn http_statuses_are_classified() {
+        let error = |status| TraceError::from(format!("request failed with status {status}"));
+        assert_eq!(classify_export_error(&error(400)), ExportErrorKind::Fatal);
+        assert_eq!(classify_export_error(&error(401)), ExportErrorKind::Fatal);
+        assert_eq!(
+            classify_export_error(&error(408)),
+            ExportErrorKind::Retryable
+        );
+        assert_eq!(
+            classify_export_error(&error(429)),
+            ExportErrorKind::Retryable
+        );
+        assert_eq!(
+            classify_export_error(&error(503)),
+            ExportErrorKind::Retryable
+        );
+    }
+
+    #[test]
+    fn grpc_statuses_are_classified() {
+        for fatal in [
+            "Client specified an invalid argument",
+            "The request does not have valid authentication credentials",
+            "The caller does not have permission to execute the specified operation",
+            "Operation is not implemented or not supported",
+        ] {
+            let kind = classify_export_error(&grpc_error(fatal));
+            assert_eq!(kind, ExportErrorKind::Fatal, "{fatal}");
+        }
+        for retryable in [
+            "The service is currently unavailable",
+            "Deadline expired before operation could complete",
+            "Some resource has been exhausted",
+        ] {
+            let kind = classify_export_error(&grpc_error(retryable));
+            assert_eq!(kind, ExportErrorKind::Retryable, "{retryable}");
+        }
+    }
+
+    #[test]
+    fn unclassified_errors_are_retryable() {
+        let error = TraceError::from("connection refused");
+        assert_eq!(classify_export_error(&error), ExportErrorKind::Retryable);
+        let error = TraceError::ExportTimedOut(Duration::from_secs(1));
+        assert_eq!(classify_export_error(&error), ExportErrorKind::Retryable);
+    }
+
+    // Fails every export with the error of `message`
+    #[derive(Debug, Clone)]
+    struct Failing {
+        message: &'static str,
+        attempts: Arc<AtomicUsize>,
+    }
+
+    impl SpanExporter for Failing {
+        fn export(&mut self, _batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            let message = self.message;
+            Box::pin(async move { Err(TraceError::from(message)) })
+        }
+    }
+
+    fn export_attempts(message: &'static str) -> usize {
+        let failing = Failing {
+            message,
+            attempts: Arc::default(),
+        };
+        let policy = RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        let mut exporter = RetryingSpanExporter::new(failing.clone(), policy);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        assert!(runtime.block_on(exporter.export(Vec::new())).is_err());
+        failing.attempts.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn fatal_errors_are_not_retried() {
+        let unauthenticated = "the grpc server returns error (The request does not have valid \
+                               authentication credentials): ";
+        assert_eq!(export_attempts(unauthenticated), 1);
+        assert_eq!(export_attempts("request failed with status 400"), 1);
+    }
+
+    #[test]
+    fn retryable_errors_are_retried_up_to_the_limit() {
+        assert_eq!(export_attempts("request failed with status 503"), 4);
+    }
+
+    #[cfg(feature = "tonic")]
+    #[test]
+    fn grpc_errors_of_the_otlp_exporter_are_classified() {
+        let status = tonic::Status::permission_denied("denied");
+        let error = TraceError::from(opentelemetry_otlp::Error::from(status));
+        assert_eq!(classify_export_error(&error), ExportErrorKind::Fatal);
+
+        let status = tonic::Status::unavailable("down");
+        let error = TraceError::from(opentelemetry_otlp::Error::from(status));
+        assert_eq!(classify_export_error(&error), ExportErrorKind::Retryable);
+    }
+}
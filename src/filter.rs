@@ -0,0 +1,55 @@
+use tracing::{level_filters::LevelFilter, Event, Metadata, Subscriber};
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::{
+    layer::{Context, Filter},
+    registry::LookupSpan,
+};
+
+use crate::trace::trace_flags_from_otel_data;
+
+// Per-layer filter for the OpenTelemetry layer: every span is recorded, but only events at or
+// above `level` are attached to their span as span events. Other layers (like the `Json` output)
+// keep seeing all events, since the filter only applies to the layer it is attached to.
+#[derive(Debug, Clone, Copy)]
+pub struct SpanEventFilter {
+    level: LevelFilter,
+}
+
+impl SpanEventFilter {
+    pub fn new(level: impl Into<LevelFilter>) -> Self {
+        Self {
+            level: level.into(),
+        }
+    }
+}
+
+impl<S> Filter<S> for SpanEventFilter {
+    fn enabled(&self, meta: &Metadata<'_>, _cx: &Context<'_, S>) -> bool {
+        meta.is_span() || meta.level() <= &self.level
+    }
+}
+
+// Per-layer filter for the `Json` output: drops the events within spans of a trace that isn't
+// sampled, so everything logged can be looked up in the tracing backend. Events outside of any
+// span, in spans not recorded by the OpenTelemetry layer, or in a local root span whose
+// sampling decision wasn't taken yet are kept.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SampledTraceFilter;
+
+impl<S> Filter<S> for SampledTraceFilter
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    fn enabled(&self, _meta: &Metadata<'_>, _cx: &Context<'_, S>) -> bool {
+        true
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, cx: &Context<'_, S>) -> bool {
+        let Some(span) = cx.event_span(event) else {
+            return true;
+        };
+        let ext = span.extensions();
+        let trace_flags = ext.get::<OtelData>().and_then(trace_flags_from_otel_data);
+        trace_flags.is_none_or(|trace_flags| trace_flags.is_sampled())
+    }
+}
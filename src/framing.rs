@@ -0,0 +1,528 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex, Once, PoisonError},
+    time::{Duration, Instant},
+};
+
+use serde::de::IgnoredAny;
+use tracing::Metadata;
+use tracing_subscriber::fmt::MakeWriter;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Records are passed through as is, the formatters terminate each of them with a newline.
+    #[default]
+    Newline,
+    /// Each record is prefixed with its length as big-endian `u32`, for binary transports.
+    LengthPrefixed,
+}
+
+// `MakeWriter` which frames every record the fmt layer writes. The fmt layer creates one writer
+// per event, so with `LengthPrefixed` the record is buffered and written together with its
+// length prefix once the writer is dropped.
+#[derive(Debug, Clone)]
+pub struct FramedMakeWriter<M> {
+    inner: M,
+    framing: Framing,
+}
+
+impl<M> FramedMakeWriter<M> {
+    pub fn new(inner: M, framing: Framing) -> Self {
+        Self { inner, framing }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for FramedMakeWriter<M> {
+    type Writer = FramedWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        FramedWriter::new(self.inner.make_writer(), self.framing)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        FramedWriter::new(self.inner.make_writer_for(meta), self.framing)
+    }
+}
+
+pub struct FramedWriter<W: Write> {
+    inner: W,
+    framing: Framing,
+    record: Vec<u8>,
+}
+
+impl<W: Write> FramedWriter<W> {
+    fn new(inner: W, framing: Framing) -> Self {
+        Self {
+            inner,
+            framing,
+            record: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for FramedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.framing {
+            Framing::Newline => self.inner.write(buf),
+            Framing::LengthPrefixed => {
+                self.record.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.framing {
+            Framing::Newline => self.inner.flush(),
+            // the record is only complete once the writer is dropped
+            Framing::LengthPrefixed => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for FramedWriter<W> {
+    fn drop(&mut self) {
+        if self.framing != Framing::LengthPrefixed || self.record.is_empty() {
+            return;
+        }
+
+        // the reader would reject the frame and lose its place in the stream, so drop the record
+        if self.record.len() > MAX_RECORD_LEN {
+            opentelemetry::global::handle_error(opentelemetry::global::Error::Other(format!(
+                "dropping a log record of {} bytes, longer than the maximum of {MAX_RECORD_LEN}",
+                self.record.len()
+            )));
+            return;
+        }
+        let len = self.record.len() as u32;
+
+        // errors can't be reported from `drop`, the fmt layer ignores write errors anyway
+        let _ = self
+            .inner
+            .write_all(&len.to_be_bytes())
+            .and_then(|_| self.inner.write_all(&self.record))
+            .and_then(|_| self.inner.flush());
+    }
+}
+
+/// The longest record `read_length_prefixed` accepts, so a corrupt or hostile length prefix
+/// can't make it allocate up to 4 GiB. `FramedWriter` drops longer records instead of writing
+/// frames the reader would reject.
+pub const MAX_RECORD_LEN: usize = 16 * 1024 * 1024;
+
+// Read the next `Framing::LengthPrefixed` record, `None` at the end of the stream. A stream
+// ending within a record, including its length prefix, is an `UnexpectedEof` error.
+pub fn read_length_prefixed(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let read = loop {
+        match reader.read(&mut len) {
+            Ok(read) => break read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    };
+    if read == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[read..])?;
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_RECORD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("record length {len} exceeds the maximum of {MAX_RECORD_LEN}"),
+        ));
+    }
+
+    let mut record = vec![0; len];
+    reader.read_exact(&mut record)?;
+    Ok(Some(record))
+}
+
+// `MakeWriter` accumulating the formatted JSON objects and writing them as a single JSON array
+// `[{...},{...}]` for bulk ingestion APIs, see `array_elements` for records of several lines. A batch is flushed once it holds `max_records`
+// records or, when the next record arrives, the oldest one is older than `max_age`. Remaining
+// records are flushed when `flush` is called on shutdown, or when the last clone is dropped.
+#[derive(Clone)]
+pub struct JsonArrayBatcher<W: Write> {
+    shared: Arc<Batch<W>>,
+}
+
+struct Batch<W: Write> {
+    state: Mutex<BatchState<W>>,
+    max_records: usize,
+    max_age: Duration,
+}
+
+struct BatchState<W> {
+    writer: W,
+    records: Vec<Vec<u8>>,
+    oldest: Option<Instant>,
+}
+
+impl<W: Write> JsonArrayBatcher<W> {
+    pub fn new(writer: W, max_records: usize, max_age: Duration) -> Self {
+        Self {
+            shared: Arc::new(Batch {
+                state: Mutex::new(BatchState {
+                    writer,
+                    records: Vec::new(),
+                    oldest: None,
+                }),
+                max_records: max_records.max(1),
+                max_age,
+            }),
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.shared.lock().flush()
+    }
+}
+
+impl<W: Write> Batch<W> {
+    fn lock(&self) -> std::sync::MutexGuard<'_, BatchState<W>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn push(&self, record: Vec<u8>) -> io::Result<()> {
+        let elements = array_elements(&record);
+        if elements.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = self.lock();
+        let oldest = *state.oldest.get_or_insert_with(Instant::now);
+        state
+            .records
+            .extend(elements.into_iter().map(<[u8]>::to_vec));
+
+        if state.records.len() >= self.max_records || oldest.elapsed() >= self.max_age {
+            state.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// The JSON objects of a record, each an element of the array. Formatters like `OneLinePerField`
+// write several per event, the newlines terminating them are dropped as the array delimits them
+// now. Content which isn't a JSON object, like the human-readable line of `HumanAndJson`, can't
+// be part of the array and is dropped.
+fn array_elements(record: &[u8]) -> Vec<&[u8]> {
+    let mut elements = Vec::new();
+    let mut values = serde_json::Deserializer::from_slice(record).into_iter::<IgnoredAny>();
+    loop {
+        let start = values.byte_offset();
+        match values.next() {
+            None => return elements,
+            Some(Ok(_)) => {
+                let element = record[start..values.byte_offset()].trim_ascii_start();
+                if !element.starts_with(b"{") {
+                    break;
+                }
+                elements.push(element);
+            }
+            Some(Err(_)) => break,
+        }
+    }
+
+    // the objects are still delimited by newlines, unless pretty-printed
+    let lines = record.split(|b| *b == b'\n').map(<[u8]>::trim_ascii);
+    let (objects, others): (Vec<_>, Vec<_>) = lines
+        .filter(|line| !line.is_empty())
+        .partition(|line| line.starts_with(b"{") && is_json(line));
+    if !others.is_empty() {
+        report_dropped_content();
+    }
+    objects
+}
+
+fn is_json(line: &[u8]) -> bool {
+    serde_json::from_slice::<IgnoredAny>(line).is_ok()
+}
+
+fn report_dropped_content() {
+    static REPORTED: Once = Once::new();
+    REPORTED.call_once(|| {
+        opentelemetry::global::handle_error(opentelemetry::global::Error::Other(
+            "dropping log output which isn't a JSON object from the JSON array batch".into(),
+        ));
+    });
+}
+
+impl<W: Write> BatchState<W> {
+    fn flush(&mut self) -> io::Result<()> {
+        self.oldest = None;
+        if self.records.is_empty() {
+            return Ok(());
+        }
+
+        let mut array =
+            Vec::with_capacity(self.records.iter().map(|r| r.len() + 1).sum::<usize>() + 2);
+        array.push(b'[');
+        for (i, record) in self.records.drain(..).enumerate() {
+            if i > 0 {
+                array.push(b',');
+            }
+            array.extend_from_slice(&record);
+        }
+        array.extend_from_slice(b"]\n");
+
+        self.writer.write_all(&array)?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for Batch<W> {
+    fn drop(&mut self) {
+        let _ = self.lock().flush();
+    }
+}
+
+impl<'a, W: Write + 'a> MakeWriter<'a> for JsonArrayBatcher<W> {
+    type Writer = BatchRecord<'a, W>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        BatchRecord {
+            batch: &self.shared,
+            record: Vec::new(),
+        }
+    }
+}
+
+// Buffers a single record, which is added to the batch once the fmt layer drops the writer
+pub struct BatchRecord<'a, W: Write> {
+    batch: &'a Batch<W>,
+    record: Vec<u8>,
+}
+
+impl<'a, W: Write> Write for BatchRecord<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.record.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> Drop for BatchRecord<'a, W> {
+    fn drop(&mut self) {
+        let _ = self.batch.push(std::mem::take(&mut self.record));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::{
+        fmt::{format::DefaultFields, FormatEvent},
+        layer::SubscriberExt,
+        Registry,
+    };
+
+    use super::*;
+    use crate::json::{HumanAndJson, Json, LineOrder, OneLinePerField};
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Output {
+        fn bytes(&self) -> Vec<u8> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MakeWriter<'_> for Output {
+        type Writer = Self;
+
+        fn make_writer(&self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    fn write_record(writer: &FramedMakeWriter<Output>, record: &[u8]) {
+        let mut writer = writer.make_writer();
+        // written in two parts, like the fmt layer may do
+        let (head, tail) = record.split_at(record.len() / 2);
+        writer.write_all(head).unwrap();
+        writer.write_all(tail).unwrap();
+    }
+
+    fn read_all(mut bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut records = Vec::new();
+        while let Some(record) = read_length_prefixed(&mut bytes).unwrap() {
+            records.push(record);
+        }
+        records
+    }
+
+    #[test]
+    fn length_prefixed_records_round_trip() {
+        let output = Output::default();
+        let writer = FramedMakeWriter::new(output.clone(), Framing::LengthPrefixed);
+        write_record(&writer, b"first\n");
+        write_record(&writer, b"second\n");
+
+        assert_eq!(&output.bytes()[..4], &6u32.to_be_bytes());
+        assert_eq!(read_all(&output.bytes()), [&b"first\n"[..], b"second\n"]);
+    }
+
+    #[test]
+    fn newline_framing_passes_records_through() {
+        let output = Output::default();
+        let writer = FramedMakeWriter::new(output.clone(), Framing::Newline);
+        write_record(&writer, b"first\n");
+        write_record(&writer, b"second\n");
+
+        assert_eq!(output.bytes(), b"first\nsecond\n");
+    }
+
+    #[test]
+    fn formatted_events_can_be_read_back_and_decoded() {
+        let output = Output::default();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .event_format(Json::default())
+                .with_writer(FramedMakeWriter::new(
+                    output.clone(),
+                    Framing::LengthPrefixed,
+                )),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(answer = 42, "first");
+            tracing::info!("second");
+        });
+
+        let records = read_all(&output.bytes());
+        assert_eq!(records.len(), 2);
+        let first: serde_json::Value = serde_json::from_slice(&records[0]).unwrap();
+        assert_eq!(first["fields"]["message"], "first");
+        assert_eq!(first["fields"]["answer"], 42);
+    }
+
+    #[test]
+    fn oversized_records_are_dropped_without_corrupting_the_stream() {
+        let output = Output::default();
+        let writer = FramedMakeWriter::new(output.clone(), Framing::LengthPrefixed);
+        write_record(&writer, b"before\n");
+        write_record(&writer, &vec![b'x'; MAX_RECORD_LEN + 1]);
+        write_record(&writer, b"after\n");
+
+        assert_eq!(read_all(&output.bytes()), [&b"before\n"[..], b"after\n"]);
+    }
+
+    #[test]
+    fn records_at_the_limit_are_kept() {
+        let output = Output::default();
+        let writer = FramedMakeWriter::new(output.clone(), Framing::LengthPrefixed);
+        write_record(&writer, &vec![b'x'; MAX_RECORD_LEN]);
+
+        let records = read_all(&output.bytes());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].len(), MAX_RECORD_LEN);
+    }
+
+    #[test]
+    fn oversized_length_prefixes_are_rejected() {
+        let prefix = (MAX_RECORD_LEN as u32 + 1).to_be_bytes();
+        let err = read_length_prefixed(&mut &prefix[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_streams_are_unexpected_eof() {
+        let err = read_length_prefixed(&mut &[0, 0][..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut record = 5u32.to_be_bytes().to_vec();
+        record.extend_from_slice(b"abc");
+        let err = read_length_prefixed(&mut &record[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn empty_streams_have_no_records() {
+        assert!(read_length_prefixed(&mut &[][..]).unwrap().is_none());
+    }
+
+    fn batched<E>(format: E, f: impl FnOnce()) -> Vec<serde_json::Value>
+    where
+        E: FormatEvent<Registry, DefaultFields> + Send + Sync + 'static,
+    {
+        let output = Output::default();
+        let batcher = JsonArrayBatcher::new(output.clone(), 100, Duration::from_secs(60));
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .event_format(format)
+                .with_writer(batcher.clone()),
+        );
+        tracing::subscriber::with_default(subscriber, f);
+        batcher.flush().unwrap();
+
+        let output = output.bytes();
+        assert!(output.ends_with(b"]\n"));
+        let array: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        array.as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn batches_are_json_arrays() {
+        let elements = batched(Json::default(), || {
+            for i in 0..3 {
+                tracing::info!(i, "event");
+            }
+        });
+        assert_eq!(elements.len(), 3);
+        assert_eq!(elements[2]["fields"]["i"], 2);
+    }
+
+    #[test]
+    fn batches_hold_every_object_of_multi_line_formatters() {
+        let elements = batched(OneLinePerField::new(Json::default()), || {
+            tracing::info!(a = 1, b = 2, "event");
+        });
+        assert_eq!(elements.len(), 3);
+
+        let elements = batched(Json::default().pretty(true), || {
+            tracing::info!("first");
+            tracing::info!("second");
+        });
+        assert_eq!(elements.len(), 2);
+    }
+
+    #[test]
+    fn batches_drop_the_human_lines_of_human_and_json() {
+        for order in [LineOrder::HumanFirst, LineOrder::JsonFirst] {
+            let elements = batched(HumanAndJson::new(Json::default(), order), || {
+                tracing::info!("event");
+            });
+            assert_eq!(elements.len(), 1, "{order:?}");
+            assert_eq!(elements[0]["fields"]["message"], "event");
+        }
+    }
+
+    #[test]
+    fn batches_flush_at_max_records() {
+        let output = Output::default();
+        let batcher = JsonArrayBatcher::new(output.clone(), 2, Duration::from_secs(60));
+        for record in [&b"{\"a\":1}\n"[..], b"{\"b\":2}\n", b"{\"c\":3}\n"] {
+            batcher.make_writer().write_all(record).unwrap();
+        }
+        assert_eq!(output.bytes(), b"[{\"a\":1},{\"b\":2}]\n");
+
+        drop(batcher);
+        assert_eq!(output.bytes(), b"[{\"a\":1},{\"b\":2}]\n[{\"c\":3}]\n");
+    }
+}
@@ -0,0 +1,173 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::future::BoxFuture;
+use opentelemetry::{
+    sdk::export::trace::{ExportResult, SpanData, SpanExporter},
+    trace::{SpanId, Status, TraceError},
+    Key, Value,
+};
+use opentelemetry_semantic_conventions::resource;
+use serde_json::json;
+
+// Exports spans in Jaeger's native JSON format (as used by its query API and UI uploads) for
+// Jaeger deployments without OTLP ingestion. The resource attributes become the process tags.
+// Requires the `reqwest` feature.
+#[derive(Debug)]
+pub struct JaegerJsonExporter {
+    client: reqwest::Client,
+    endpoint: String,
+}
+
+impl JaegerJsonExporter {
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            endpoint: endpoint.into(),
+        }
+    }
+}
+
+impl SpanExporter for JaegerJsonExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let request = self
+            .client
+            .post(&self.endpoint)
+            .header("content-type", "application/json")
+            .body(jaeger_json(&batch).to_string());
+
+        Box::pin(async move {
+            let response = request
+                .send()
+                .await
+                .map_err(|err| TraceError::from(err.to_string()))?;
+
+            // same wording as opentelemetry-http, so `classify_export_error` applies as well
+            let status = response.status();
+            if !status.is_success() {
+                return Err(TraceError::from(format!(
+                    "request failed with status {status}"
+                )));
+            }
+            Ok(())
+        })
+    }
+}
+
+// Convert a batch into `{"data":[{"traceID":..,"spans":[..],"processes":{..}}]}`, one entry per
+// trace. All spans of a batch come from the same tracer provider, so they share one process.
+pub fn jaeger_json(batch: &[SpanData]) -> serde_json::Value {
+    let mut traces: Vec<(String, Vec<serde_json::Value>)> = Vec::new();
+    for span in batch {
+        let trace_id = span.span_context.trace_id().to_string();
+        let span = jaeger_span(span);
+        match traces.iter_mut().find(|(id, _)| *id == trace_id) {
+            Some((_, spans)) => spans.push(span),
+            None => traces.push((trace_id, vec![span])),
+        }
+    }
+
+    let process = batch.first().map(|span| {
+        let service_name = span
+            .resource
+            .get(resource::SERVICE_NAME)
+            .map(|name| name.as_str().into_owned())
+            .unwrap_or_else(|| "unknown_service".to_owned());
+        let tags: Vec<_> = span
+            .resource
+            .iter()
+            .filter(|(key, _)| **key != resource::SERVICE_NAME)
+            .map(|(key, value)| tag(key, value))
+            .collect();
+        json!({ "serviceName": service_name, "tags": tags })
+    });
+
+    let data: Vec<_> = traces
+        .into_iter()
+        .map(|(trace_id, spans)| {
+            json!({
+                "traceID": trace_id,
+                "spans": spans,
+                "processes": { "p1": process },
+            })
+        })
+        .collect();
+    json!({ "data": data })
+}
+
+fn jaeger_span(span: &SpanData) -> serde_json::Value {
+    let trace_id = span.span_context.trace_id().to_string();
+
+    let mut references = Vec::new();
+    if span.parent_span_id != SpanId::INVALID {
+        references.push(json!({
+            "refType": "CHILD_OF",
+            "traceID": trace_id,
+            "spanID": span.parent_span_id.to_string(),
+        }));
+    }
+    for link in span.links.iter() {
+        references.push(json!({
+            "refType": "FOLLOWS_FROM",
+            "traceID": link.span_context.trace_id().to_string(),
+            "spanID": link.span_context.span_id().to_string(),
+        }));
+    }
+
+    let mut tags: Vec<_> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| tag(key, value))
+        .collect();
+    tags.push(json!({
+        "key": "span.kind",
+        "type": "string",
+        "value": format!("{:?}", span.span_kind).to_lowercase(),
+    }));
+    if let Status::Error { description } = &span.status {
+        tags.push(json!({ "key": "error", "type": "bool", "value": true }));
+        tags.push(
+            json!({ "key": "otel.status_description", "type": "string", "value": description }),
+        );
+    }
+
+    let logs: Vec<_> = span
+        .events
+        .iter()
+        .map(|event| {
+            let mut fields = vec![json!({ "key": "event", "type": "string", "value": event.name })];
+            fields.extend(event.attributes.iter().map(|kv| tag(&kv.key, &kv.value)));
+            json!({ "timestamp": micros(event.timestamp), "fields": fields })
+        })
+        .collect();
+
+    let start_time = micros(span.start_time);
+    json!({
+        "traceID": trace_id,
+        "spanID": span.span_context.span_id().to_string(),
+        "flags": span.span_context.trace_flags().to_u8(),
+        "operationName": span.name,
+        "references": references,
+        "startTime": start_time,
+        "duration": micros(span.end_time).saturating_sub(start_time),
+        "tags": tags,
+        "logs": logs,
+        "processID": "p1",
+    })
+}
+
+fn tag(key: &Key, value: &Value) -> serde_json::Value {
+    let (kind, value) = match value {
+        Value::Bool(value) => ("bool", json!(value)),
+        Value::I64(value) => ("int64", json!(value)),
+        Value::F64(value) => ("float64", json!(value)),
+        // Jaeger has no array tags, so these are flattened into their string form
+        value => ("string", json!(value.as_str())),
+    };
+    json!({ "key": key.as_str(), "type": kind, "value": value })
+}
+
+fn micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_micros() as u64)
+        .unwrap_or_default()
+}
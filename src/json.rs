@@ -1,9 +1,29 @@
-use std::{io, marker::PhantomData};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, PoisonError,
+    },
+    time::{Duration, Instant},
+};
 
-use chrono::Utc;
-use opentelemetry::trace::TraceContextExt;
+use chrono::{DateTime, Utc};
+use opentelemetry::{
+    baggage::{Baggage, BaggageExt},
+    sdk::Resource,
+    trace::{SpanId, TraceContextExt, TraceFlags},
+    Array, Value as OtelValue,
+};
 use serde::ser::{Serialize, SerializeMap, Serializer};
-use tracing::{Event, Subscriber};
+use serde_json::ser::{CompactFormatter, Formatter, PrettyFormatter};
+use tokio::task::LocalKey;
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
 use tracing_opentelemetry::OtelData;
 use tracing_serde::{fields::AsMap, AsSerde};
 use tracing_subscriber::{
@@ -11,12 +31,1726 @@ use tracing_subscriber::{
     registry::{LookupSpan, SpanRef},
 };
 
-use crate::trace::trace_info_from_ref;
+use crate::{
+    setup::is_shut_down,
+    timing::SpanTiming,
+    trace::{
+        linked_trace_ids, remote_parent_span_id, trace_flags_from_otel_data,
+        trace_info_and_flags_from_ref, trace_info_from_otel_data, xray_trace_id, TraceInfo,
+    },
+};
+
+/// The default formatter, so `event_format(json::Json)` from before `Json` became configurable
+/// still compiles. Deprecated in favor of `Json::default()` and `Json::builder()`. It can't carry
+/// a `#[deprecated]` attribute, that would also warn on every `use` of the `Json` type.
+#[allow(non_upper_case_globals)]
+pub const Json: Json = Json::new();
 
 // https://github.com/tokio-rs/tracing/blob/4e65750b13721fee7a7ac05b053e1b9c3d21244f/tracing-subscriber/src/fmt/format/json.rs
-pub struct Json;
+#[derive(Clone)]
+pub struct Json {
+    trace_id_hasher: Option<TraceIdHasher>,
+    suppressed_targets: BTreeSet<String>,
+    host_info: bool,
+    build_info: bool,
+    promoted_span_fields: Option<SpanFieldKeys>,
+    omit_empty_fields: bool,
+    id_source: IdSource,
+    span_as_string: bool,
+    target_map: Option<TargetMap>,
+    task_local_trace_id: Option<&'static LocalKey<TraceInfo>>,
+    dedup: Option<Dedup>,
+    emit_traceparent: bool,
+    emit_xray: bool,
+    max_field_len: Option<usize>,
+    root_key: Option<String>,
+    escalate_on_error: bool,
+    emit_span_name: bool,
+    emit_span_elapsed: bool,
+    emit_baggage: bool,
+    error_stack: bool,
+    id_placement: IdPlacement,
+    sequence: bool,
+    span_attribute_limit: Option<u32>,
+    data_prepper: bool,
+    trace_flags_decimal: bool,
+    span_list: bool,
+    flatten_fields: bool,
+    partition_fields: bool,
+    timestamp_source: TimestampSource,
+    timestamp_format: TimestampFormat,
+    root_span_id: RootSpanId,
+    source_location: bool,
+    thread_info: bool,
+    post_shutdown: PostShutdown,
+    field_types: Option<FieldTypes>,
+    emit_sampled: bool,
+    emit_module: bool,
+    mark_lossy_fields: bool,
+    default_message: Option<String>,
+    pretty: bool,
+    key_renames: BTreeMap<&'static str, &'static str>,
+    fallback_on_error: bool,
+    resource: Option<Arc<serde_json::Map<String, serde_json::Value>>>,
+    flatten_resource: bool,
+}
+
+/// Chainable configuration of a `Json` formatter, see `Json::builder`. Each setter is the one
+/// of `Json` with the same name.
+#[derive(Clone, Default)]
+pub struct JsonBuilder(Json);
+
+impl JsonBuilder {
+    pub fn build(self) -> Json {
+        self.0
+    }
+}
+
+impl From<Json> for JsonBuilder {
+    fn from(json: Json) -> Self {
+        Self(json)
+    }
+}
+
+impl From<JsonBuilder> for Json {
+    fn from(builder: JsonBuilder) -> Self {
+        builder.build()
+    }
+}
+
+macro_rules! builder_setters {
+    ($($setter:ident($value:ty);)*) => {
+        impl JsonBuilder {
+            $(
+                #[doc = concat!("See `Json::", stringify!($setter), "`.")]
+                pub fn $setter(self, value: $value) -> Self {
+                    Self(self.0.$setter(value))
+                }
+            )*
+        }
+    };
+}
+
+builder_setters! {
+    trace_id_hasher(Option<TraceIdHasher>);
+    suppressed_targets(HashSet<String>);
+    with_host_info(bool);
+    with_build_info(bool);
+    promote_span_fields(Option<SpanFieldKeys>);
+    omit_empty_fields(bool);
+    id_source(IdSource);
+    span_as_string(bool);
+    target_map(TargetMap);
+    with_task_local_trace_id(&'static LocalKey<TraceInfo>);
+    dedup_window(Option<Duration>);
+    emit_traceparent(bool);
+    with_span_list(bool);
+    timestamp_source(TimestampSource);
+    timestamp_format(TimestampFormat);
+    with_partition_fields(bool);
+    flatten_fields(bool);
+    mark_lossy_fields(bool);
+    default_message(Option<String>);
+    pretty(bool);
+    rename_keys(HashMap<&'static str, &'static str>);
+    emit_module(bool);
+    with_source_location(bool);
+    with_thread_info(bool);
+    post_shutdown(PostShutdown);
+    field_types(HashMap<String, FieldType>);
+    root_span_id(RootSpanId);
+    emit_sampled(bool);
+    trace_flags_decimal(bool);
+    emit_xray(bool);
+    max_field_len(Option<usize>);
+    root_key(Option<String>);
+    escalate_on_error(bool);
+    emit_span_name(bool);
+    emit_span_elapsed(bool);
+    emit_baggage(bool);
+    with_error_stack(bool);
+    id_placement(IdPlacement);
+    with_sequence(bool);
+    span_attribute_limit(Option<u32>);
+    fallback_on_error(bool);
+    resource(Option<Resource>);
+    flatten_resource(bool);
+}
+
+/// Keys emitted by the formatter itself, which are never altered by field transformations.
+/// Options like `with_host_info` or `rename_keys` reserve further keys while enabled.
+pub const RESERVED_KEYS: &[&str] = &[
+    "timestamp",
+    "seq",
+    "date",
+    "hour",
+    "level",
+    "severity",
+    "fields",
+    "field_lossy",
+    "error.stack",
+    "target",
+    "module",
+    "file",
+    "line",
+    "module_path",
+    "threadName",
+    "threadId",
+    "span",
+    "spans",
+    "span_name",
+    "span_elapsed_ms",
+    "baggage",
+    "span_id",
+    "root_span_id",
+    "trace_id",
+    "traceparent",
+    "trace_flags_decimal",
+    "sampled",
+    "remote_parent_span_id",
+    "traceId",
+    "spanId",
+    "traceGroup",
+    "post_shutdown",
+    "resource",
+    "_format_error",
+];
+
+/// How the keys of span fields promoted to the top level are built: `{prefix}{separator}{field}`.
+#[derive(Debug, Clone, Default)]
+pub struct SpanFieldKeys {
+    pub separator: KeySeparator,
+    pub prefix: KeyPrefix,
+}
+
+impl SpanFieldKeys {
+    fn key(&self, span_name: &str, field: &str) -> String {
+        let prefix = match &self.prefix {
+            KeyPrefix::SpanName => span_name,
+            KeyPrefix::Fixed(prefix) => prefix,
+        };
+        format!("{prefix}{}{field}", self.separator.as_str())
+    }
+}
+
+/// Separator between prefix and field name. Log stores differ in whether they treat `.` as nesting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeySeparator {
+    #[default]
+    Dot,
+    Underscore,
+    Colon,
+}
+
+impl KeySeparator {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Dot => ".",
+            Self::Underscore => "_",
+            Self::Colon => ":",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum KeyPrefix {
+    /// Prefix with the name of the span the field belongs to.
+    #[default]
+    SpanName,
+    Fixed(String),
+}
+
+/// Where the emitted `trace_id`/`span_id` are read from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdSource {
+    /// The `OtelData` extension of the current span, as recorded by the OpenTelemetry layer.
+    #[default]
+    SpanExtensions,
+    /// `opentelemetry::Context::current()`, for layer orderings where it's the authoritative one.
+    CurrentContext,
+    /// The current span's `OtelData`, falling back to the current context if there is none.
+    PreferSpanThenContext,
+}
+
+/// The JSON type `Json::field_types` coerces a field to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+impl FieldType {
+    fn matches(self, value: &serde_json::Value) -> bool {
+        match self {
+            Self::String => value.is_string(),
+            Self::Int => value.is_i64() || value.is_u64(),
+            Self::Float => value.is_f64(),
+            Self::Bool => value.is_boolean(),
+        }
+    }
+
+    fn coerce(self, value: &serde_json::Value) -> Option<serde_json::Value> {
+        use serde_json::Value;
+
+        match (self, value) {
+            (Self::String, value) => Some(value.to_string().into()),
+            (Self::Int, Value::String(value)) => {
+                let value = value.trim();
+                (value.parse::<i64>().map(Value::from))
+                    .or_else(|_| value.parse::<u64>().map(Value::from))
+                    .ok()
+            }
+            (Self::Float, Value::Number(number)) => number.as_f64().map(Value::from),
+            (Self::Float, Value::String(value)) => {
+                value.trim().parse::<f64>().ok().map(Value::from)
+            }
+            (Self::Bool, Value::String(value)) => {
+                value.trim().parse::<bool>().ok().map(Value::from)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct FieldTypes {
+    types: Arc<HashMap<String, FieldType>>,
+    // names of the fields a mismatch was already reported for
+    reported: Arc<Mutex<HashSet<String>>>,
+}
+
+impl FieldTypes {
+    // Values which can't be coerced (like a non-numeric string declared as `Int`) are kept
+    fn apply(&self, fields: &mut serde_json::Map<String, serde_json::Value>) {
+        for (field, value) in fields.iter_mut() {
+            let Some(field_type) = self.types.get(field).copied() else {
+                continue;
+            };
+            if field_type.matches(value) {
+                continue;
+            }
+
+            self.report(field, field_type);
+            if let Some(coerced) = field_type.coerce(value) {
+                *value = coerced;
+            }
+        }
+    }
+
+    // Reported once per field through the global OpenTelemetry error handler, as logging it
+    // from within the formatter would recurse
+    fn report(&self, field: &str, field_type: FieldType) {
+        let mut reported = self.reported.lock().unwrap_or_else(PoisonError::into_inner);
+        if reported.insert(field.to_owned()) {
+            opentelemetry::global::handle_error(opentelemetry::global::Error::Other(format!(
+                "field `{field}` was recorded with another type than the declared {field_type:?}"
+            )));
+        }
+    }
+}
+
+/// Where `span_id` and `trace_id` are emitted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdPlacement {
+    #[default]
+    TopLevel,
+    /// Inside the `span` object. Events outside of any span keep them at the top level.
+    Span,
+    Both,
+}
+
+/// When the span id of the outermost span is emitted as `root_span_id`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RootSpanId {
+    #[default]
+    Never,
+    /// Only if the current span is nested, i.e. `root_span_id` would differ from `span_id`.
+    WhenNested,
+    Always,
+}
+
+/// What happens to events emitted after `setup::shutdown_tracer_provider`, which have no
+/// OpenTelemetry context to read ids from anymore.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PostShutdown {
+    /// Emit them without ids but with a `post_shutdown: true` marker.
+    #[default]
+    Mark,
+    /// Produce no output for them.
+    Drop,
+}
+
+/// Where the emitted `timestamp` is read from.
+#[derive(Clone, Default)]
+pub enum TimestampSource {
+    #[default]
+    WallClock,
+    /// Seconds since the process started (precisely, since the formatter was configured) as a
+    /// number, for environments without a reliable wall clock.
+    MonotonicSinceStart,
+    /// An injected clock, e.g. a fixed one for deterministic output.
+    Clock(Clock),
+}
+
+pub type Clock = Arc<dyn Fn() -> DateTime<Utc> + Send + Sync>;
+
+/// How the emitted `timestamp` is formatted. `TimestampSource::MonotonicSinceStart` is
+/// always emitted as seconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// A string like `2024-01-02T03:04:05.123456789+00:00`.
+    #[default]
+    Rfc3339,
+    /// Milliseconds since the Unix epoch as a number.
+    EpochMillis,
+    /// Nanoseconds since the Unix epoch as a number. These only fit between the years 1677 and
+    /// 2262, timestamps outside (e.g. of an injected clock) are emitted as RFC 3339 strings.
+    EpochNanos,
+}
+
+// The time an event is formatted at, as read from the configured `TimestampSource`
+enum Timestamp {
+    DateTime(DateTime<Utc>),
+    SecondsSinceStart(f64),
+}
+
+impl Timestamp {
+    fn date_time(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Self::DateTime(date_time) => Some(*date_time),
+            Self::SecondsSinceStart(_) => None,
+        }
+    }
+
+    fn to_value(&self, format: TimestampFormat) -> serde_json::Value {
+        match (self, format) {
+            (Self::DateTime(date_time), TimestampFormat::Rfc3339) => date_time.to_rfc3339().into(),
+            (Self::DateTime(date_time), TimestampFormat::EpochMillis) => {
+                date_time.timestamp_millis().into()
+            }
+            (Self::DateTime(date_time), TimestampFormat::EpochNanos) => {
+                match date_time.timestamp_nanos_opt() {
+                    Some(nanos) => nanos.into(),
+                    None => date_time.to_rfc3339().into(),
+                }
+            }
+            (Self::SecondsSinceStart(seconds), _) => (*seconds).into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DateTime(date_time) => f.write_str(&date_time.to_rfc3339()),
+            Self::SecondsSinceStart(seconds) => write!(f, "{seconds:.6}"),
+        }
+    }
+}
+
+fn process_start() -> Instant {
+    static START: OnceLock<Instant> = OnceLock::new();
+    *START.get_or_init(Instant::now)
+}
+
+/// Rewrites the emitted `target`, e.g. to shorten long module paths.
+pub type TargetMap = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+// Process-wide rather than per `Json`, so layers like the ones of `setup::split_by_level` share it
+static EVENT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Transforms an emitted `trace_id`/`span_id`, e.g. to hash it before it leaves the process.
+pub type TraceIdHasher = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+impl Default for Json {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Json {
+    pub const fn new() -> Self {
+        Self {
+            trace_id_hasher: None,
+            suppressed_targets: BTreeSet::new(),
+            host_info: false,
+            build_info: false,
+            promoted_span_fields: None,
+            omit_empty_fields: false,
+            id_source: IdSource::SpanExtensions,
+            span_as_string: false,
+            target_map: None,
+            task_local_trace_id: None,
+            dedup: None,
+            emit_traceparent: false,
+            emit_xray: false,
+            max_field_len: None,
+            root_key: None,
+            escalate_on_error: false,
+            emit_span_name: false,
+            emit_span_elapsed: false,
+            emit_baggage: false,
+            error_stack: false,
+            id_placement: IdPlacement::TopLevel,
+            sequence: false,
+            span_attribute_limit: None,
+            data_prepper: false,
+            trace_flags_decimal: false,
+            span_list: false,
+            flatten_fields: false,
+            partition_fields: false,
+            timestamp_source: TimestampSource::WallClock,
+            timestamp_format: TimestampFormat::Rfc3339,
+            root_span_id: RootSpanId::Never,
+            source_location: false,
+            thread_info: false,
+            post_shutdown: PostShutdown::Mark,
+            field_types: None,
+            emit_sampled: false,
+            emit_module: false,
+            mark_lossy_fields: false,
+            default_message: None,
+            pretty: false,
+            key_renames: BTreeMap::new(),
+            fallback_on_error: false,
+            resource: None,
+            flatten_resource: false,
+        }
+    }
+
+    /// A builder starting from the default configuration, `Json::builder().build()` is
+    /// equivalent to `Json::default()`.
+    pub fn builder() -> JsonBuilder {
+        JsonBuilder::default()
+    }
+
+    /// Preset for OpenSearch Data Prepper's trace to log correlation, which expects the ids as
+    /// `traceId` and `spanId` plus the name of the trace's root span as `traceGroup`.
+    pub fn data_prepper() -> Self {
+        Self {
+            data_prepper: true,
+            ..Self::default()
+        }
+    }
+
+    /// Apply `hasher` to the emitted `trace_id` and `span_id`, as well as to the other ids like
+    /// `root_span_id` and `linked_trace_ids`. The entries which need the raw ids to be valid,
+    /// `traceparent` and the X-Ray ones, are omitted then. When `None` the raw ids are emitted.
+    pub fn trace_id_hasher(mut self, hasher: Option<TraceIdHasher>) -> Self {
+        self.trace_id_hasher = hasher;
+        self
+    }
+
+    /// Produce no output for events whose target is (or is nested below) one of `targets`.
+    /// Spans of these targets are still recorded and exported by the other layers.
+    pub fn suppressed_targets(mut self, targets: HashSet<String>) -> Self {
+        self.suppressed_targets = targets.into_iter().collect();
+        self
+    }
+
+    /// Emit `host.name` and `process.pid` to tell apart the logs of multiple instances.
+    pub fn with_host_info(mut self, enabled: bool) -> Self {
+        self.host_info = enabled;
+        self
+    }
+
+    /// Emit `build.commit` (from `GIT_SHA` or `SOURCE_COMMIT`) and `build.time` (from
+    /// `BUILD_TIME`) to match logs to the deployed build, each only if its variable is set.
+    pub fn with_build_info(mut self, enabled: bool) -> Self {
+        self.build_info = enabled;
+        self
+    }
+
+    /// Emit the attributes of `resource`, like the `setup::service_resource` the spans are
+    /// exported with, as a `resource` object on every event. They're converted once here.
+    pub fn resource(mut self, resource: Option<Resource>) -> Self {
+        self.resource = resource.map(|resource| Arc::new(resource_fields(&resource)));
+        self
+    }
+
+    /// Emit the `resource` attributes at the top level, e.g. as `service.name`. An attribute
+    /// named like a key of the formatter is emitted as `resource.{name}` instead, and with
+    /// `flatten_fields` event fields named like an attribute as `fields.{name}`.
+    pub fn flatten_resource(mut self, enabled: bool) -> Self {
+        self.flatten_resource = enabled;
+        self
+    }
+
+    /// Additionally emit the fields of the current span at the top level, keyed as described by `keys`.
+    pub fn promote_span_fields(mut self, keys: Option<SpanFieldKeys>) -> Self {
+        self.promoted_span_fields = keys;
+        self
+    }
+
+    /// Skip the `fields` entry for events without any fields instead of emitting `"fields":{}`.
+    pub fn omit_empty_fields(mut self, enabled: bool) -> Self {
+        self.omit_empty_fields = enabled;
+        self
+    }
+
+    pub fn id_source(mut self, id_source: IdSource) -> Self {
+        self.id_source = id_source;
+        self
+    }
+
+    /// Emit the `span` entry as a string holding the escaped JSON object, to avoid a schema
+    /// explosion in pipelines which re-parse it later on.
+    pub fn span_as_string(mut self, enabled: bool) -> Self {
+        self.span_as_string = enabled;
+        self
+    }
+
+    /// Apply `target_map` to the event's target before it's emitted. Target based options like
+    /// `suppressed_targets` still match the original target.
+    pub fn target_map(mut self, target_map: TargetMap) -> Self {
+        self.target_map = Some(target_map);
+        self
+    }
+
+    /// Fall back to the `TraceInfo` of a task-local set by e.g. request middleware when no ids
+    /// are found otherwise, so logs still correlate in setups without the OpenTelemetry layer.
+    pub fn with_task_local_trace_id(mut self, key: &'static LocalKey<TraceInfo>) -> Self {
+        self.task_local_trace_id = Some(key);
+        self
+    }
+
+    /// Suppress consecutive identical lines (ignoring the timestamp) within `window` of the
+    /// first one. Once a different line arrives, the last suppressed line is emitted once more
+    /// with a `repeated` count, see `flush_dedup` for the last lines before an exit.
+    pub fn dedup_window(mut self, window: Option<Duration>) -> Self {
+        self.dedup = window.map(|window| Dedup {
+            window,
+            last: Default::default(),
+        });
+        self
+    }
+
+    /// Emit the `repeated` count of the lines suppressed by `dedup_window` since the last emitted
+    /// one, which otherwise only happens once a different line arrives. `setup::shutdown_tracing`
+    /// does so for the subscribers assembled by `setup::build_subscriber`.
+    pub fn flush_dedup(&self, writer: &mut dyn io::Write) -> io::Result<()> {
+        match &self.dedup {
+            Some(dedup) => dedup.flush(writer),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn has_dedup(&self) -> bool {
+        self.dedup.is_some()
+    }
+
+    fn target<'a>(&self, target: &'a str) -> Cow<'a, str> {
+        match &self.target_map {
+            Some(target_map) => Cow::Owned(target_map(target)),
+            None => Cow::Borrowed(target),
+        }
+    }
+
+    /// Additionally emit the ids and trace flags as a single W3C `traceparent` entry. Omitted
+    /// with a `trace_id_hasher`, as hashed ids don't form a valid one.
+    pub fn emit_traceparent(mut self, enabled: bool) -> Self {
+        self.emit_traceparent = enabled;
+        self
+    }
+
+    /// Additionally emit all active spans (ordered from the root to the current one) as a
+    /// `spans` array, so logs of deeply nested spans keep the parent chain.
+    pub fn with_span_list(mut self, enabled: bool) -> Self {
+        self.span_list = enabled;
+        self
+    }
+
+    /// Read the emitted `timestamp` from `source` instead of the wall clock.
+    pub fn timestamp_source(mut self, source: TimestampSource) -> Self {
+        if let TimestampSource::MonotonicSinceStart = source {
+            process_start();
+        }
+        self.timestamp_source = source;
+        self
+    }
+
+    /// Emit the `timestamp` as `format`, e.g. as epoch milliseconds for collectors expecting
+    /// a number.
+    pub fn timestamp_format(mut self, format: TimestampFormat) -> Self {
+        self.timestamp_format = format;
+        self
+    }
+
+    // Read once per event before anything else is done, so all entries derived from it agree
+    fn timestamp(&self) -> Timestamp {
+        match &self.timestamp_source {
+            TimestampSource::WallClock => Timestamp::DateTime(Utc::now()),
+            TimestampSource::MonotonicSinceStart => {
+                Timestamp::SecondsSinceStart(process_start().elapsed().as_secs_f64())
+            }
+            TimestampSource::Clock(clock) => Timestamp::DateTime(clock()),
+        }
+    }
+
+    /// Additionally emit the UTC `date` (`YYYY-MM-DD`) and `hour` (`HH`) of the timestamp, for
+    /// storage partitioned by date which shouldn't need to parse the timestamp.
+    pub fn with_partition_fields(mut self, enabled: bool) -> Self {
+        self.partition_fields = enabled;
+        self
+    }
+
+    /// Emit the event's fields as top-level entries instead of nesting them under `fields`.
+    /// A field named like one of the `RESERVED_KEYS` or a key of another enabled option (e.g.
+    /// `host.name`, or a key chosen through `rename_keys`) is emitted as `fields.{name}`
+    /// instead, so it never overrides the formatter's own entries.
+    pub fn flatten_fields(mut self, enabled: bool) -> Self {
+        self.flatten_fields = enabled;
+        self
+    }
+
+    /// Emit `field_lossy: true` for events with a string field holding U+FFFD replacement
+    /// characters, as produced by `LossyUtf8` for invalid UTF-8 input.
+    pub fn mark_lossy_fields(mut self, enabled: bool) -> Self {
+        self.mark_lossy_fields = enabled;
+        self
+    }
+
+    /// Emit the entries `timestamp`, `level`, `target`, `span_id` and `trace_id` under other
+    /// names, e.g. `trace_id` as `tid`. Event fields are never renamed, unmapped keys are kept.
+    pub fn rename_keys(mut self, renames: HashMap<&'static str, &'static str>) -> Self {
+        self.key_renames = renames.into_iter().collect();
+        self
+    }
+
+    /// Indent the output over multiple lines for reading it in a terminal during development.
+    pub fn pretty(mut self, enabled: bool) -> Self {
+        self.pretty = enabled;
+        self
+    }
+
+    /// Substitute `message` for the message of events without one or with a blank one, so log
+    /// viewers don't show empty rows. Such events are emitted without a message when `None`.
+    pub fn default_message(mut self, message: Option<String>) -> Self {
+        self.default_message = message;
+        self
+    }
+
+    /// Additionally emit the first `::` separated segment of the target (usually the crate) as
+    /// `module`. Matches the original target, not the one rewritten by `target_map`.
+    pub fn emit_module(mut self, enabled: bool) -> Self {
+        self.emit_module = enabled;
+        self
+    }
+
+    /// Additionally emit the event's source location as `file`, `line` and `module_path`. Each
+    /// is omitted if the event's metadata lacks it.
+    pub fn with_source_location(mut self, enabled: bool) -> Self {
+        self.source_location = enabled;
+        self
+    }
+
+    /// Additionally emit the `threadName` and `threadId` of the thread formatting the event.
+    /// Unnamed threads get their id as name.
+    pub fn with_thread_info(mut self, enabled: bool) -> Self {
+        self.thread_info = enabled;
+        self
+    }
+
+    pub fn post_shutdown(mut self, post_shutdown: PostShutdown) -> Self {
+        self.post_shutdown = post_shutdown;
+        self
+    }
+
+    /// Coerce the named event fields to the declared types, e.g. a `user_id` recorded as a
+    /// string to a number, so all lines agree on the schema. A mismatch is reported once per
+    /// field through the global OpenTelemetry error handler.
+    pub fn field_types(mut self, types: HashMap<String, FieldType>) -> Self {
+        self.field_types = Some(FieldTypes {
+            types: Arc::new(types),
+            reported: Default::default(),
+        });
+        self
+    }
+
+    /// Additionally emit the span id of the outermost span as `root_span_id`, to group all logs
+    /// of a request under its entry span.
+    pub fn root_span_id(mut self, mode: RootSpanId) -> Self {
+        self.root_span_id = mode;
+        self
+    }
+
+    /// Additionally emit whether the trace is sampled as a `sampled` boolean. Omitted along with
+    /// the ids when there's no valid trace.
+    pub fn emit_sampled(mut self, enabled: bool) -> Self {
+        self.emit_sampled = enabled;
+        self
+    }
+
+    /// Additionally emit the trace flags as a `trace_flags_decimal` integer, e.g. `1` if sampled.
+    pub fn trace_flags_decimal(mut self, enabled: bool) -> Self {
+        self.trace_flags_decimal = enabled;
+        self
+    }
+
+    /// Additionally emit `AWS.XRAY.TraceId` (in X-Ray's `1-{8 hex}-{24 hex}` form) and
+    /// `AWS.XRAY.SegmentId` so CloudWatch can correlate the logs with X-Ray traces. Omitted with
+    /// a `trace_id_hasher`, as hashed ids aren't of the form X-Ray expects.
+    pub fn emit_xray(mut self, enabled: bool) -> Self {
+        self.emit_xray = enabled;
+        self
+    }
+
+    /// Truncate string field values longer than `max_len` chars, marking them with an ellipsis
+    /// and a `{field}_truncated: true` sibling. Fields named like a reserved key are exempt.
+    pub fn max_field_len(mut self, max_len: Option<usize>) -> Self {
+        self.max_field_len = max_len;
+        self
+    }
+
+    /// Nest the whole output object under `root_key`, e.g. `{"log":{...}}`.
+    pub fn root_key(mut self, root_key: Option<String>) -> Self {
+        self.root_key = root_key;
+        self
+    }
+
+    /// Additionally emit a `severity`, which is the event's level bumped one step (e.g. `INFO`
+    /// to `WARN`) when the event carries a field recorded as an error. `level` stays as is.
+    pub fn escalate_on_error(mut self, enabled: bool) -> Self {
+        self.escalate_on_error = enabled;
+        self
+    }
+
+    /// Additionally emit the name of the current span as a top-level `span_name`, for quick
+    /// filtering. Omitted for events outside of any span.
+    pub fn emit_span_name(mut self, enabled: bool) -> Self {
+        self.emit_span_name = enabled;
+        self
+    }
+
+    /// Emit for how long the current span has been running as `span_elapsed_ms`, measured from
+    /// the start time recorded by the OpenTelemetry layer. Omitted for events outside of any span.
+    pub fn emit_span_elapsed(mut self, enabled: bool) -> Self {
+        self.emit_span_elapsed = enabled;
+        self
+    }
+
+    /// Emit a process-wide counter increasing by one per event as `seq`, so consumers can spot
+    /// lines lost in transport by gaps. The counter doesn't tell lines apart for `dedup_window`,
+    /// so collapsed lines leave gaps as well.
+    pub fn with_sequence(mut self, enabled: bool) -> Self {
+        self.sequence = enabled;
+        self
+    }
+
+    /// Emit the `span_id` and `trace_id` at the top level, in the `span` object or in both.
+    pub fn id_placement(mut self, placement: IdPlacement) -> Self {
+        self.id_placement = placement;
+        self
+    }
+
+    /// For ERROR events with a field recorded as `error = &err as &dyn std::error::Error`, emit
+    /// the messages of the error and its `source()` chain as an `error.stack` array.
+    pub fn with_error_stack(mut self, enabled: bool) -> Self {
+        self.error_stack = enabled;
+        self
+    }
+
+    /// Emit the OpenTelemetry baggage of the current span's context (e.g. extracted from an
+    /// inbound request) as a `baggage` object. Off by default, since baggage may hold values
+    /// which shouldn't end up in logs.
+    pub fn emit_baggage(mut self, enabled: bool) -> Self {
+        self.emit_baggage = enabled;
+        self
+    }
+
+    /// Emit a `dropped_attributes` count in the `span` entry when the span holds more
+    /// attributes than `limit`, which has to match the tracer's `max_attributes_per_span`.
+    pub fn span_attribute_limit(mut self, limit: Option<u32>) -> Self {
+        self.span_attribute_limit = limit;
+        self
+    }
+
+    /// When an event can't be serialized, emit a minimal line with the timestamp, level, raw
+    /// message and the error as `_format_error` instead of dropping it. The entries are placed
+    /// like in the regular lines, as configured by `root_key`, `rename_keys` and `flatten_fields`. Lines are buffered
+    /// then, so a failure doesn't leave a partial line behind.
+    pub fn fallback_on_error(mut self, enabled: bool) -> Self {
+        self.fallback_on_error = enabled;
+        self
+    }
+
+    // The trace flags are unknown when the ids come from the task-local fallback
+    pub(crate) fn trace_info<S>(
+        &self,
+        span_ref: Option<&SpanRef<'_, S>>,
+    ) -> Option<(TraceInfo, Option<TraceFlags>)>
+    where
+        S: for<'lookup> LookupSpan<'lookup>,
+    {
+        let from_span = || {
+            let ext = span_ref?.extensions();
+            let otel_data = ext.get::<OtelData>()?;
+            trace_info_from_otel_data(otel_data)
+                .map(|info| (info, trace_flags_from_otel_data(otel_data)))
+        };
+        let from_context = || {
+            let cx = opentelemetry::Context::current();
+            trace_info_and_flags_from_ref(cx.span()).map(|(info, flags)| (info, Some(flags)))
+        };
+
+        let trace_info = match self.id_source {
+            IdSource::SpanExtensions => from_span(),
+            IdSource::CurrentContext => from_context(),
+            IdSource::PreferSpanThenContext => from_span().or_else(from_context),
+        };
+
+        trace_info.or_else(|| {
+            self.task_local_trace_id?
+                .try_with(|trace_info| (trace_info.clone(), None))
+                .ok()
+        })
+    }
+
+    // `{timestamp} INFO [trace_id=.. span_id=..] target: message key=value`, the ids are omitted
+    // outside of a trace. Shared by all human-readable outputs, so their lines look the same.
+    pub(crate) fn human_line<S, N>(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        event: &Event<'_>,
+        color_trace_ids: bool,
+    ) -> Result<String, std::fmt::Error>
+    where
+        S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+        N: for<'writer> FormatFields<'writer> + 'static,
+    {
+        use std::fmt::Write;
+
+        let meta = event.metadata();
+        let mut line = format!("{} {:>5} ", self.timestamp(), meta.level());
+
+        if let Some((trace_info, _)) = self.trace_info(ctx.parent_span().as_ref()) {
+            let trace_id = self.hash_id(&trace_info.trace_id);
+            match color_trace_ids {
+                true => write!(
+                    line,
+                    "[trace_id=\x1b[{}m{trace_id}\x1b[0m",
+                    trace_id_color(&trace_id)
+                )?,
+                false => write!(line, "[trace_id={trace_id}")?,
+            }
+            write!(line, " span_id={}] ", self.hash_id(&trace_info.span_id))?;
+        }
+
+        write!(line, "{}:", self.target(meta.target()))?;
+        event.record(&mut PlainTextFields(&mut line));
+        Ok(line)
+    }
+
+    // The event's fields with `field_types` and `max_field_len` applied
+    fn collect_fields(&self, event: &Event<'_>) -> serde_json::Map<String, serde_json::Value> {
+        let mut fields = collect_fields(event);
+        let is_blank = match fields.get("message") {
+            Some(serde_json::Value::String(message)) => message.trim().is_empty(),
+            Some(_) => false,
+            None => true,
+        };
+        if is_blank {
+            match &self.default_message {
+                Some(default_message) => {
+                    fields.insert("message".into(), default_message.as_str().into());
+                }
+                None => {
+                    fields.remove("message");
+                }
+            }
+        }
+        if let Some(field_types) = &self.field_types {
+            field_types.apply(&mut fields);
+        }
+        match self.max_field_len {
+            Some(max_len) => truncate_fields(fields, max_len),
+            None => fields,
+        }
+    }
+
+    pub(crate) fn is_suppressed(&self, target: &str) -> bool {
+        self.suppressed_targets.iter().any(|suppressed| {
+            target
+                .strip_prefix(suppressed.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
+    }
+
+    fn key(&self, key: &'static str) -> &'static str {
+        self.key_renames.get(key).copied().unwrap_or(key)
+    }
+
+    // Whether the formatter itself emits `key` at the top level with the enabled options
+    fn is_reserved(&self, key: &str) -> bool {
+        self.is_formatter_key(key)
+            || (self.flatten_resource
+                && self
+                    .resource
+                    .as_ref()
+                    .is_some_and(|resource| resource.contains_key(key)))
+    }
+
+    // Like `is_reserved`, without the flattened resource attributes
+    fn is_formatter_key(&self, key: &str) -> bool {
+        RESERVED_KEYS.contains(&key)
+            || self.key_renames.values().any(|renamed| *renamed == key)
+            || (self.host_info && matches!(key, "host.name" | "process.pid"))
+            || (self.build_info && matches!(key, "build.commit" | "build.time"))
+            || (self.emit_xray && matches!(key, "AWS.XRAY.TraceId" | "AWS.XRAY.SegmentId"))
+    }
+
+    fn fallback_line(
+        &self,
+        timestamp: &serde_json::Value,
+        level: Level,
+        message: Option<String>,
+        error: &serde_json::Error,
+    ) -> String {
+        let mut line = serde_json::Map::new();
+        line.insert(self.key("timestamp").into(), timestamp.clone());
+        line.insert(self.key("level").into(), level.as_str().into());
+        if let Some(message) = message {
+            match self.flatten_fields {
+                true => line.insert("message".into(), message.into()),
+                false => line.insert("fields".into(), serde_json::json!({ "message": message })),
+            };
+        }
+        line.insert("_format_error".into(), error.to_string().into());
+
+        // wrapped like the regular lines, so they end up in the same place
+        let line = match &self.root_key {
+            Some(root_key) => serde_json::json!({ root_key: line }),
+            None => serde_json::Value::Object(line),
+        };
+        line.to_string()
+    }
+
+    pub(crate) fn hash_id(&self, id: &str) -> String {
+        match &self.trace_id_hasher {
+            Some(hasher) => hasher(id),
+            None => id.to_owned(),
+        }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for Json
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        // records forwarded by `setup::init_log_bridge` carry the `log` call site in fields
+        #[cfg(feature = "log")]
+        let normalized = tracing_log::NormalizeEvent::normalized_metadata(event);
+        #[cfg(feature = "log")]
+        let meta = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        #[cfg(not(feature = "log"))]
+        let meta = event.metadata();
+
+        if self.is_suppressed(meta.target()) {
+            return Ok(());
+        }
+        let shut_down = is_shut_down();
+        if shut_down && self.post_shutdown == PostShutdown::Drop {
+            return Ok(());
+        }
+
+        let timestamp = self.timestamp();
+        let timestamp_value = timestamp.to_value(self.timestamp_format);
+        let seq = self
+            .sequence
+            .then(|| EVENT_SEQUENCE.fetch_add(1, Ordering::Relaxed));
+
+        let visit = |out: &mut dyn std::fmt::Write| {
+            let mut serializer = serde_json::Serializer::with_formatter(
+                WriteAdaptor::new(out),
+                LineFormatter::new(self.pretty),
+            );
+            let mut serializer = serializer.serialize_map(None)?;
+            serializer.serialize_entry(self.key("timestamp"), &timestamp_value)?;
+            if let Some(seq) = seq {
+                serializer.serialize_entry("seq", &seq)?;
+            }
+            // without a wall clock there's no date to derive the partition from
+            if let Some(now) = timestamp.date_time().filter(|_| self.partition_fields) {
+                serializer.serialize_entry("date", &now.format("%Y-%m-%d").to_string())?;
+                serializer.serialize_entry("hour", &now.format("%H").to_string())?;
+            }
+            serializer.serialize_entry(self.key("level"), &meta.level().as_serde())?;
+            if self.escalate_on_error {
+                let severity = match has_error_field(event) {
+                    true => escalate(*meta.level()),
+                    false => *meta.level(),
+                };
+                serializer.serialize_entry("severity", &severity.as_serde())?;
+            }
+            if self.flatten_fields {
+                for (field, value) in self.collect_fields(event) {
+                    match self.is_reserved(&field) {
+                        true => serializer.serialize_entry(&format!("fields.{field}"), &value)?,
+                        false => serializer.serialize_entry(&field, &value)?,
+                    }
+                }
+            } else if !self.omit_empty_fields
+                || has_recorded_fields(event)
+                || self.default_message.is_some()
+            {
+                match self.max_field_len.is_some()
+                    || self.field_types.is_some()
+                    || self.default_message.is_some()
+                    || has_blank_message(event)
+                {
+                    true => serializer.serialize_entry("fields", &self.collect_fields(event))?,
+                    false => serializer.serialize_entry("fields", &event.field_map())?,
+                }
+            }
+            if self.mark_lossy_fields && has_lossy_field(event) {
+                serializer.serialize_entry("field_lossy", &true)?;
+            }
+            if self.error_stack && *meta.level() == Level::ERROR {
+                if let Some(error_stack) = error_stack(event) {
+                    serializer.serialize_entry("error.stack", &error_stack)?;
+                }
+            }
+            serializer.serialize_entry(self.key("target"), &self.target(meta.target()))?;
+            if self.emit_module {
+                let module = meta.target().split("::").next().unwrap_or_default();
+                serializer.serialize_entry("module", module)?;
+            }
+            if self.source_location {
+                if let Some(file) = meta.file() {
+                    serializer.serialize_entry("file", file)?;
+                }
+                if let Some(line) = meta.line() {
+                    serializer.serialize_entry("line", &line)?;
+                }
+                if let Some(module_path) = meta.module_path() {
+                    serializer.serialize_entry("module_path", module_path)?;
+                }
+            }
+
+            if self.thread_info {
+                let thread = std::thread::current();
+                // `ThreadId::as_u64` is unstable, so the number is taken from its `Debug` output
+                let thread_id = format!("{:?}", thread.id());
+                let thread_id = thread_id
+                    .strip_prefix("ThreadId(")
+                    .and_then(|id| id.strip_suffix(')'))
+                    .unwrap_or(&thread_id);
+                serializer.serialize_entry("threadName", thread.name().unwrap_or(thread_id))?;
+                serializer.serialize_entry("threadId", thread_id)?;
+            }
+
+            if self.host_info {
+                let host_info = host_info();
+                if let Some(host_name) = &host_info.name {
+                    serializer.serialize_entry("host.name", host_name)?;
+                }
+                serializer.serialize_entry("process.pid", &host_info.pid)?;
+            }
+
+            if self.build_info {
+                let build_info = build_info();
+                if let Some(commit) = &build_info.commit {
+                    serializer.serialize_entry("build.commit", commit)?;
+                }
+                if let Some(time) = &build_info.time {
+                    serializer.serialize_entry("build.time", time)?;
+                }
+            }
+
+            if let Some(resource) = &self.resource {
+                match self.flatten_resource {
+                    true => {
+                        for (key, value) in resource.iter() {
+                            match self.is_formatter_key(key) {
+                                true => {
+                                    serializer.serialize_entry(&format!("resource.{key}"), value)?
+                                }
+                                false => serializer.serialize_entry(key, value)?,
+                            }
+                        }
+                    }
+                    false => serializer.serialize_entry("resource", &**resource)?,
+                }
+            }
+
+            let format_field_marker: PhantomData<N> = PhantomData;
+
+            // respects explicitly set event parents, like the one of a span's close event
+            let current_span = ctx.parent_span();
+
+            let trace_info = match shut_down {
+                true => None,
+                false => self.trace_info(current_span.as_ref()),
+            };
+            let span_ids = trace_info
+                .as_ref()
+                .filter(|_| current_span.is_some() && self.id_placement != IdPlacement::TopLevel)
+                .map(|(trace_info, _)| {
+                    BTreeMap::from([
+                        (self.key("span_id"), self.hash_id(&trace_info.span_id)),
+                        (self.key("trace_id"), self.hash_id(&trace_info.trace_id)),
+                    ])
+                });
+
+            if let Some(span_ref) = &current_span {
+                if self.emit_span_name {
+                    serializer.serialize_entry("span_name", span_ref.name())?;
+                }
+
+                if self.emit_span_elapsed {
+                    let start_time = span_ref
+                        .extensions()
+                        .get::<OtelData>()
+                        .and_then(|otel_data| otel_data.builder.start_time);
+                    // a clock set back since the span started wouldn't yield a duration
+                    let elapsed = start_time.and_then(|start| start.elapsed().ok());
+                    if let Some(elapsed) = elapsed {
+                        serializer.serialize_entry(
+                            "span_elapsed_ms",
+                            &(elapsed.as_secs_f64() * 1000.0),
+                        )?;
+                    }
+                }
+
+                let span = SpanWithIds {
+                    span: SerializableSpan(
+                        span_ref,
+                        format_field_marker,
+                        self.span_attribute_limit,
+                        self.trace_id_hasher.as_ref(),
+                    ),
+                    ids: span_ids.as_ref(),
+                };
+                if self.span_as_string {
+                    serializer.serialize_entry("span", &serde_json::to_string(&span)?)?;
+                } else {
+                    serializer.serialize_entry("span", &span)?;
+                }
+
+                if self.span_list {
+                    let spans = SerializableSpanList(
+                        span_ref,
+                        format_field_marker,
+                        self.span_attribute_limit,
+                        self.trace_id_hasher.as_ref(),
+                    );
+                    serializer.serialize_entry("spans", &spans)?;
+                }
+
+                if let Some(keys) = &self.promoted_span_fields {
+                    let ext = span_ref.extensions();
+                    let fields = ext
+                        .get::<FormattedFields<N>>()
+                        .and_then(|data| serde_json::from_str::<serde_json::Value>(data).ok());
+
+                    if let Some(serde_json::Value::Object(fields)) = fields {
+                        for (field, value) in fields {
+                            serializer
+                                .serialize_entry(&keys.key(span_ref.name(), &field), &value)?;
+                        }
+                    }
+                }
+            }
+
+            if self.emit_baggage {
+                // the parent context a span was created with carries the baggage, events outside
+                // of any span can only see the one of an attached OpenTelemetry context
+                let baggage = match &current_span {
+                    Some(span_ref) => span_ref
+                        .extensions()
+                        .get::<OtelData>()
+                        .map(|otel_data| baggage_entries(otel_data.parent_cx.baggage())),
+                    None => Some(baggage_entries(opentelemetry::Context::current().baggage())),
+                };
+                if let Some(baggage) = baggage.filter(|baggage| !baggage.is_empty()) {
+                    serializer.serialize_entry("baggage", &baggage)?;
+                }
+            }
+
+            if shut_down {
+                serializer.serialize_entry("post_shutdown", &true)?;
+            }
+            if let Some((trace_info, trace_flags)) = trace_info {
+                let span_id = self.hash_id(&trace_info.span_id);
+                let trace_id = self.hash_id(&trace_info.trace_id);
+                if self.data_prepper {
+                    serializer.serialize_entry("spanId", &span_id)?;
+                    serializer.serialize_entry("traceId", &trace_id)?;
+
+                    let root_span = current_span.as_ref().and_then(|span| span.scope().last());
+                    if let Some(root_span) = root_span {
+                        serializer.serialize_entry("traceGroup", root_span.name())?;
+                    }
+                } else if span_ids.is_none() || self.id_placement == IdPlacement::Both {
+                    serializer.serialize_entry(self.key("span_id"), &span_id)?;
+                    serializer.serialize_entry(self.key("trace_id"), &trace_id)?;
+                }
+
+                let root_span = current_span
+                    .as_ref()
+                    .and_then(|span| span.scope().last())
+                    .filter(|root_span| match self.root_span_id {
+                        RootSpanId::Never => false,
+                        RootSpanId::WhenNested => {
+                            current_span.as_ref().map(SpanRef::id) != Some(root_span.id())
+                        }
+                        RootSpanId::Always => true,
+                    });
+                // read from the builder, as a root span has no parent context to fall back to
+                let root_span_id = root_span.and_then(|root_span| {
+                    let span_id = root_span.extensions().get::<OtelData>()?.builder.span_id?;
+                    (span_id != SpanId::INVALID).then(|| span_id.to_string())
+                });
+                if let Some(root_span_id) = root_span_id {
+                    serializer.serialize_entry("root_span_id", &self.hash_id(&root_span_id))?;
+                }
+
+                let remote_parent_span_id = current_span.as_ref().and_then(|span| {
+                    span.extensions()
+                        .get::<OtelData>()
+                        .and_then(remote_parent_span_id)
+                });
+                if let Some(remote_parent_span_id) = remote_parent_span_id {
+                    serializer.serialize_entry(
+                        "remote_parent_span_id",
+                        &self.hash_id(&remote_parent_span_id),
+                    )?;
+                }
+
+                // hashed ids aren't of the hex form X-Ray expects, and the raw ones mustn't leak
+                let xray_trace_id = xray_trace_id(&trace_info.trace_id)
+                    .filter(|_| self.emit_xray && self.trace_id_hasher.is_none());
+                if let Some(xray_trace_id) = xray_trace_id {
+                    serializer.serialize_entry("AWS.XRAY.TraceId", &xray_trace_id)?;
+                    serializer.serialize_entry("AWS.XRAY.SegmentId", &trace_info.span_id)?;
+                }
+
+                if let Some(trace_flags) = trace_flags.filter(|_| self.emit_sampled) {
+                    serializer.serialize_entry("sampled", &trace_flags.is_sampled())?;
+                }
+
+                if let Some(trace_flags) = trace_flags.filter(|_| self.trace_flags_decimal) {
+                    serializer.serialize_entry("trace_flags_decimal", &trace_flags.to_u8())?;
+                }
+
+                // hashed ids don't form a valid `traceparent`, and the raw ones mustn't leak
+                let emit_traceparent = self.emit_traceparent && self.trace_id_hasher.is_none();
+                if let Some(trace_flags) = trace_flags.filter(|_| emit_traceparent) {
+                    serializer
+                        .serialize_entry("traceparent", &trace_info.to_traceparent(trace_flags))?;
+                }
+            }
+
+            serializer.end()
+        };
+
+        let write_error = <serde_json::Error as serde::ser::Error>::custom;
+        let render = |out: &mut dyn std::fmt::Write| match &self.root_key {
+            Some(root_key) => {
+                let root_key = serde_json::to_string(root_key)?;
+                write!(out, "{{{root_key}:").map_err(write_error)?;
+                visit(out)?;
+                out.write_char('}').map_err(write_error)
+            }
+            None => visit(out),
+        };
+
+        if self.dedup.is_none() && !self.fallback_on_error {
+            render(&mut writer).map_err(|_| std::fmt::Error)?;
+            return writeln!(writer);
+        }
+
+        let mut line = String::new();
+        if let Err(err) = render(&mut line) {
+            if !self.fallback_on_error {
+                return Err(std::fmt::Error);
+            }
+            let message = raw_message(event);
+            line = self.fallback_line(&timestamp_value, *meta.level(), message, &err);
+        }
+        match &self.dedup {
+            Some(dedup) => dedup.write(line, &timestamp_value.to_string(), seq, &mut writer),
+            None => writeln!(writer, "{line}"),
+        }
+    }
+}
+
+// Renders a human-readable line like `{timestamp} INFO [trace_id=..] target: message key=value`
+// and wraps it as `{"log":"..."}`, for transports which only accept a plain text `log` field.
+// The ids are resolved and transformed as configured on the wrapped `Json`.
+#[derive(Clone, Default)]
+pub struct PlainTextInJson {
+    json: Json,
+}
+
+impl PlainTextInJson {
+    pub fn new(json: Json) -> Self {
+        Self { json }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for PlainTextInJson
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        if self.json.is_suppressed(event.metadata().target()) {
+            return Ok(());
+        }
+
+        let line = self.json.human_line(ctx, event, false)?;
+        let line = serde_json::to_string(&line).map_err(|_| std::fmt::Error)?;
+        writeln!(writer, r#"{{"log":{line}}}"#)
+    }
+}
+
+/// Which of the two lines `HumanAndJson` writes first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineOrder {
+    #[default]
+    HumanFirst,
+    JsonFirst,
+}
+
+// Writes a human-readable summary line (as rendered by `PlainTextInJson`) next to the full
+// `Json` line of every event, both with the same ids. Doubles the log volume, so it's meant to
+// be switched on e.g. during incident response only.
+#[derive(Clone, Default)]
+pub struct HumanAndJson {
+    json: Json,
+    order: LineOrder,
+    color_trace_ids: bool,
+}
+
+impl HumanAndJson {
+    pub fn new(json: Json, order: LineOrder) -> Self {
+        Self {
+            json,
+            order,
+            color_trace_ids: false,
+        }
+    }
+
+    /// Color the trace id of the human-readable line by an ANSI color derived from it, so the
+    /// lines of one trace stand out among interleaved ones in a terminal.
+    pub fn color_trace_ids(mut self, enabled: bool) -> Self {
+        self.color_trace_ids = enabled;
+        self
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for HumanAndJson
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        if self.json.is_suppressed(event.metadata().target()) {
+            return Ok(());
+        }
+
+        let human_line = self.json.human_line(ctx, event, self.color_trace_ids)?;
+        match self.order {
+            LineOrder::HumanFirst => {
+                writeln!(writer, "{human_line}")?;
+                self.json.format_event(ctx, writer, event)
+            }
+            LineOrder::JsonFirst => {
+                self.json.format_event(ctx, writer.by_ref(), event)?;
+                writeln!(writer, "{human_line}")
+            }
+        }
+    }
+}
+
+// Emits a narrow line per recorded field of an event instead of one wide JSON object, for
+// pipelines built around single key-value events. The message gets a line of its own as
+// `"message":..`, every other field one as `"field":{name},"value":{value}`. All lines carry
+// the same timestamp, level, target and ids.
+#[derive(Clone, Default)]
+pub struct OneLinePerField {
+    json: Json,
+}
+
+impl OneLinePerField {
+    pub fn new(json: Json) -> Self {
+        Self { json }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for OneLinePerField
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let meta = event.metadata();
+        if self.json.is_suppressed(meta.target()) {
+            return Ok(());
+        }
+
+        let mut head = serde_json::Map::new();
+        head.insert(
+            self.json.key("timestamp").into(),
+            self.json.timestamp().to_value(self.json.timestamp_format),
+        );
+        head.insert(self.json.key("level").into(), meta.level().as_str().into());
+        head.insert(
+            self.json.key("target").into(),
+            self.json.target(meta.target()).into(),
+        );
+        if let Some((trace_info, _)) = self.json.trace_info(ctx.parent_span().as_ref()) {
+            head.insert(
+                self.json.key("span_id").into(),
+                self.json.hash_id(&trace_info.span_id).into(),
+            );
+            head.insert(
+                self.json.key("trace_id").into(),
+                self.json.hash_id(&trace_info.trace_id).into(),
+            );
+        }
+
+        for (field, value) in collect_fields(event) {
+            let mut line = head.clone();
+            if field == "message" {
+                line.insert(field, value);
+            } else {
+                line.insert("field".into(), field.into());
+                line.insert("value".into(), value);
+            }
+            let line = serde_json::to_string(&line).map_err(|_| std::fmt::Error)?;
+            writeln!(writer, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The keys `Ecs` emits itself. Event fields named like one of these are emitted as
+/// `fields.{name}` instead.
+pub const ECS_KEYS: &[&str] = &[
+    "@timestamp",
+    "log.level",
+    "log.logger",
+    "message",
+    "ecs.version",
+    "trace.id",
+    "span.id",
+    "tracing.span",
+];
+
+// Emits the same data as `Json` under Elastic Common Schema keys, e.g. `@timestamp`,
+// `log.level`, `log.logger`, `trace.id` and `span.id`. The dotted keys are written literally
+// rather than as nested objects, so they stay as is with Elasticsearch's dot expansion turned
+// off. The message and the other event fields are emitted at the top level, the current span
+// as `tracing.span`.
+#[derive(Clone, Default)]
+pub struct Ecs {
+    json: Json,
+}
+
+impl Ecs {
+    pub fn new(json: Json) -> Self {
+        Self { json }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for Ecs
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let meta = event.metadata();
+        if self.json.is_suppressed(meta.target()) {
+            return Ok(());
+        }
+
+        let visit = |out: &mut dyn std::fmt::Write| {
+            let mut serializer = serde_json::Serializer::new(WriteAdaptor::new(out));
+            let mut serializer = serializer.serialize_map(None)?;
+            serializer.serialize_entry(
+                "@timestamp",
+                &self.json.timestamp().to_value(self.json.timestamp_format),
+            )?;
+            serializer.serialize_entry("log.level", &meta.level().as_str().to_lowercase())?;
+            serializer.serialize_entry("log.logger", &self.json.target(meta.target()))?;
+            serializer.serialize_entry("ecs.version", "8.11")?;
+
+            for (field, value) in collect_fields(event) {
+                match field != "message" && ECS_KEYS.contains(&field.as_str()) {
+                    true => serializer.serialize_entry(&format!("fields.{field}"), &value)?,
+                    false => serializer.serialize_entry(&field, &value)?,
+                }
+            }
+
+            let current_span = ctx.parent_span();
+            if let Some(span_ref) = &current_span {
+                let span = SerializableSpan(
+                    span_ref,
+                    PhantomData::<N>,
+                    None,
+                    self.json.trace_id_hasher.as_ref(),
+                );
+                serializer.serialize_entry("tracing.span", &span)?;
+            }
+
+            if let Some((trace_info, _)) = self.json.trace_info(current_span.as_ref()) {
+                serializer.serialize_entry("trace.id", &self.json.hash_id(&trace_info.trace_id))?;
+                serializer.serialize_entry("span.id", &self.json.hash_id(&trace_info.span_id))?;
+            }
+
+            serializer.end()
+        };
+
+        visit(&mut writer).map_err(|_| std::fmt::Error)?;
+        writeln!(writer)
+    }
+}
+
+// Emits the shape of an OTLP log record, so console lines match the exported ones: the message
+// as `body`, the level as `severity_text` and `severity_number`, the ids and `trace_flags` at
+// the top level and all other event fields nested under `attributes`. The target is emitted as
+// the name of the `instrumentation_scope`.
+// https://opentelemetry.io/docs/specs/otel/logs/data-model/
+#[derive(Clone, Default)]
+pub struct OtlpJson {
+    json: Json,
+}
+
+impl OtlpJson {
+    pub fn new(json: Json) -> Self {
+        Self { json }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for OtlpJson
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let meta = event.metadata();
+        if self.json.is_suppressed(meta.target()) {
+            return Ok(());
+        }
+
+        let visit = |out: &mut dyn std::fmt::Write| {
+            let mut attributes = collect_fields(event);
+            let body = attributes.remove("message");
+
+            let mut serializer = serde_json::Serializer::new(WriteAdaptor::new(out));
+            let mut serializer = serializer.serialize_map(None)?;
+            serializer.serialize_entry(
+                "timestamp",
+                &self.json.timestamp().to_value(self.json.timestamp_format),
+            )?;
+            serializer.serialize_entry("severity_text", meta.level().as_str())?;
+            serializer.serialize_entry("severity_number", &otlp_severity_number(*meta.level()))?;
+            if let Some(body) = body {
+                serializer.serialize_entry("body", &body)?;
+            }
+
+            let current_span = ctx.parent_span();
+            if let Some((trace_info, trace_flags)) = self.json.trace_info(current_span.as_ref()) {
+                serializer.serialize_entry("trace_id", &self.json.hash_id(&trace_info.trace_id))?;
+                serializer.serialize_entry("span_id", &self.json.hash_id(&trace_info.span_id))?;
+                if let Some(trace_flags) = trace_flags {
+                    serializer.serialize_entry("trace_flags", &trace_flags.to_u8())?;
+                }
+            }
+
+            serializer.serialize_entry(
+                "instrumentation_scope",
+                &serde_json::json!({ "name": self.json.target(meta.target()) }),
+            )?;
+            serializer.serialize_entry("attributes", &attributes)?;
+            serializer.end()
+        };
+
+        visit(&mut writer).map_err(|_| std::fmt::Error)?;
+        writeln!(writer)
+    }
+}
+
+// https://opentelemetry.io/docs/specs/otel/logs/data-model/#field-severitynumber
+fn otlp_severity_number(level: Level) -> u8 {
+    match level {
+        Level::TRACE => 1,
+        Level::DEBUG => 5,
+        Level::INFO => 9,
+        Level::WARN => 13,
+        Level::ERROR => 17,
+    }
+}
+
+// The keys `GcpJson` emits itself, event fields named like one are emitted as `fields.{name}`
+const GCP_KEYS: &[&str] = &[
+    "timestamp",
+    "severity",
+    "target",
+    "span",
+    "logging.googleapis.com/trace",
+    "logging.googleapis.com/spanId",
+    "logging.googleapis.com/trace_sampled",
+];
+
+// Emits Cloud Logging's structured logging format, which correlates entries with traces via
+// `logging.googleapis.com/trace` as `projects/{project_id}/traces/{trace_id}` and
+// `logging.googleapis.com/spanId`. The level is mapped to Cloud Logging's `severity` strings.
+#[derive(Clone, Default)]
+pub struct GcpJson {
+    json: Json,
+    project_id: String,
+}
+
+impl GcpJson {
+    pub fn new(json: Json, project_id: impl Into<String>) -> Self {
+        Self {
+            json,
+            project_id: project_id.into(),
+        }
+    }
+}
 
-impl<S, N> FormatEvent<S, N> for Json
+impl<S, N> FormatEvent<S, N> for GcpJson
 where
     S: Subscriber + for<'lookup> LookupSpan<'lookup>,
     N: for<'writer> FormatFields<'writer> + 'static,
@@ -26,52 +1760,477 @@ where
         ctx: &FmtContext<'_, S, N>,
         mut writer: Writer<'_>,
         event: &Event<'_>,
-    ) -> std::fmt::Result
-    where
-        S: Subscriber + for<'a> LookupSpan<'a>,
-    {
+    ) -> std::fmt::Result {
         let meta = event.metadata();
+        if self.json.is_suppressed(meta.target()) {
+            return Ok(());
+        }
 
-        let mut visit = || {
-            let mut serializer = serde_json::Serializer::new(WriteAdaptor::new(&mut writer));
+        let visit = |out: &mut dyn std::fmt::Write| {
+            let mut serializer = serde_json::Serializer::new(WriteAdaptor::new(out));
             let mut serializer = serializer.serialize_map(None)?;
-            serializer.serialize_entry("timestamp", &Utc::now().to_rfc3339())?;
-            serializer.serialize_entry("level", &meta.level().as_serde())?;
-            serializer.serialize_entry("fields", &event.field_map())?;
-            serializer.serialize_entry("target", meta.target())?;
+            serializer.serialize_entry(
+                "timestamp",
+                &self.json.timestamp().to_value(self.json.timestamp_format),
+            )?;
+            serializer.serialize_entry("severity", gcp_severity(*meta.level()))?;
+            serializer.serialize_entry("target", &self.json.target(meta.target()))?;
 
-            let format_field_marker: PhantomData<N> = PhantomData;
+            for (field, value) in collect_fields(event) {
+                match GCP_KEYS.contains(&field.as_str()) {
+                    true => serializer.serialize_entry(&format!("fields.{field}"), &value)?,
+                    false => serializer.serialize_entry(&field, &value)?,
+                }
+            }
 
-            if let Some(span_ref) = ctx.lookup_current() {
-                serializer
-                    .serialize_entry("span", &SerializableSpan(&span_ref, format_field_marker))
-                    .unwrap_or(());
-
-                let trace_info = span_ref.extensions().get::<OtelData>().and_then(|o| {
-                    trace_info_from_ref(o.parent_cx.span()).map(|mut info| {
-                        // if the SpanBuilder contains a valid span_id we use its span_id instead
-                        // of the extracted one, because it refers to the more accurate span.
-                        if let Some(span_id) = o.builder.span_id {
-                            info.span_id = span_id.to_string();
-                        }
-                        info
-                    })
-                });
+            let current_span = ctx.parent_span();
+            if let Some(span_ref) = &current_span {
+                let span = SerializableSpan(
+                    span_ref,
+                    PhantomData::<N>,
+                    None,
+                    self.json.trace_id_hasher.as_ref(),
+                );
+                serializer.serialize_entry("span", &span)?;
+            }
 
-                if let Some(trace_info) = trace_info {
-                    serializer.serialize_entry("span_id", &trace_info.span_id)?;
-                    serializer.serialize_entry("trace_id", &trace_info.trace_id)?;
+            if let Some((trace_info, trace_flags)) = self.json.trace_info(current_span.as_ref()) {
+                let trace = format!(
+                    "projects/{}/traces/{}",
+                    self.project_id,
+                    self.json.hash_id(&trace_info.trace_id)
+                );
+                serializer.serialize_entry("logging.googleapis.com/trace", &trace)?;
+                serializer.serialize_entry(
+                    "logging.googleapis.com/spanId",
+                    &self.json.hash_id(&trace_info.span_id),
+                )?;
+                if let Some(trace_flags) = trace_flags {
+                    serializer.serialize_entry(
+                        "logging.googleapis.com/trace_sampled",
+                        &trace_flags.is_sampled(),
+                    )?;
                 }
             }
 
             serializer.end()
         };
 
-        visit().map_err(|_| std::fmt::Error)?;
+        visit(&mut writer).map_err(|_| std::fmt::Error)?;
         writeln!(writer)
     }
 }
 
+// https://cloud.google.com/logging/docs/reference/v2/rest/v2/LogEntry#logseverity
+fn gcp_severity(level: Level) -> &'static str {
+    match level {
+        Level::TRACE | Level::DEBUG => "DEBUG",
+        Level::INFO => "INFO",
+        Level::WARN => "WARNING",
+        Level::ERROR => "ERROR",
+    }
+}
+
+// The foreground colors readable on both dark and light terminals, black and white are left out
+const TRACE_ID_COLORS: [u8; 12] = [31, 32, 33, 34, 35, 36, 91, 92, 93, 94, 95, 96];
+
+// FNV-1a rather than the std hasher, whose output may change between Rust releases, so a trace
+// keeps its color across builds
+fn trace_id_color(trace_id: &str) -> u8 {
+    let hash = trace_id.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    TRACE_ID_COLORS[(hash % TRACE_ID_COLORS.len() as u64) as usize]
+}
+
+// `message` is written as is, all other fields as `key=value` with their `Debug` output
+struct PlainTextFields<'a>(&'a mut String);
+
+impl Visit for PlainTextFields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        use std::fmt::Write;
+
+        let _ = match field.name() {
+            "message" => write!(self.0, " {value:?}"),
+            name => write!(self.0, " {name}={value:?}"),
+        };
+    }
+}
+
+#[derive(Clone)]
+struct Dedup {
+    window: Duration,
+    last: Arc<Mutex<Option<DedupLine>>>,
+}
+
+struct DedupLine {
+    key: String,
+    line: String,
+    emitted_at: Instant,
+    repeated: usize,
+}
+
+impl Dedup {
+    fn write(
+        &self,
+        line: String,
+        timestamp: &str,
+        seq: Option<u64>,
+        writer: &mut Writer<'_>,
+    ) -> std::fmt::Result {
+        // identical lines only differ in their timestamp and sequence number
+        let mut key = line.replacen(timestamp, "", 1);
+        if let Some(seq) = seq {
+            let entry = [format!("\"seq\":{seq}"), format!("\"seq\": {seq}")]
+                .into_iter()
+                .find(|entry| key.contains(entry.as_str()));
+            if let Some(entry) = entry {
+                key = key.replacen(&entry, "", 1);
+            }
+        }
+        let now = Instant::now();
+
+        let mut last = self.last.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(last) = last.as_mut() {
+            if last.key == key && now.duration_since(last.emitted_at) < self.window {
+                last.line = line;
+                last.repeated += 1;
+                return Ok(());
+            }
+
+            if let Some(summary) = last.summary() {
+                writeln!(writer, "{summary}")?;
+            }
+        }
+
+        writeln!(writer, "{line}")?;
+        *last = Some(DedupLine {
+            key,
+            line,
+            emitted_at: now,
+            repeated: 0,
+        });
+        Ok(())
+    }
+
+    fn flush(&self, writer: &mut dyn io::Write) -> io::Result<()> {
+        let mut last = self.last.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(last) = last.as_mut() else {
+            return Ok(());
+        };
+        if let Some(summary) = last.summary() {
+            writeln!(writer, "{summary}")?;
+            last.repeated = 0;
+        }
+        Ok(())
+    }
+}
+
+impl DedupLine {
+    // the line is a serialized JSON object, so the count can be appended as last entry
+    fn summary(&self) -> Option<String> {
+        let entries = self.line.strip_suffix('}').filter(|_| self.repeated > 0)?;
+        Some(format!("{entries},\"repeated\":{}}}", self.repeated))
+    }
+}
+
+// Collect the recorded fields of an event, encoded the same way `tracing_serde` serializes them
+fn collect_fields(event: &Event<'_>) -> serde_json::Map<String, serde_json::Value> {
+    #[derive(Default)]
+    struct Visitor(serde_json::Map<String, serde_json::Value>);
+
+    impl Visit for Visitor {
+        fn record_bool(&mut self, field: &Field, value: bool) {
+            self.0.insert(field.name().into(), value.into());
+        }
+
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.0.insert(field.name().into(), value.into());
+        }
+
+        fn record_i64(&mut self, field: &Field, value: i64) {
+            self.0.insert(field.name().into(), value.into());
+        }
+
+        fn record_f64(&mut self, field: &Field, value: f64) {
+            self.0.insert(field.name().into(), value.into());
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().into(), value.into());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().into(), format!("{value:?}").into());
+        }
+    }
+
+    let mut visitor = Visitor::default();
+    event.record(&mut visitor);
+    visitor.0
+}
+
+fn truncate_fields(
+    fields: serde_json::Map<String, serde_json::Value>,
+    max_len: usize,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut truncated = serde_json::Map::with_capacity(fields.len());
+    for (field, value) in fields {
+        let cut = value
+            .as_str()
+            .filter(|_| !RESERVED_KEYS.contains(&field.as_str()))
+            .and_then(|value| value.char_indices().nth(max_len))
+            .map(|(cut, _)| cut);
+
+        match (cut, value) {
+            (Some(cut), serde_json::Value::String(mut value)) => {
+                value.truncate(cut);
+                value.push('…');
+                let marker = format!("{field}_truncated");
+                truncated.insert(field, value.into());
+                truncated.insert(marker, true.into());
+            }
+            (_, value) => {
+                truncated.insert(field, value);
+            }
+        }
+    }
+    truncated
+}
+
+// The `message` of an event as formatted by its `Debug` implementation
+fn raw_message(event: &Event<'_>) -> Option<String> {
+    struct Visitor(Option<String>);
+
+    impl Visit for Visitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = Some(format!("{value:?}"));
+            }
+        }
+    }
+
+    let mut visitor = Visitor(None);
+    event.record(&mut visitor);
+    visitor.0
+}
+
+// Whether the event has a `message` consisting of whitespace only
+fn has_blank_message(event: &Event<'_>) -> bool {
+    struct Visitor(bool);
+
+    impl Visit for Visitor {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "message" {
+                self.0 = value.trim().is_empty();
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.0 = format!("{value:?}").trim().is_empty();
+            }
+        }
+    }
+
+    let mut visitor = Visitor(false);
+    event.record(&mut visitor);
+    visitor.0
+}
+
+// Whether any field of the event carries a value, fields declared as `Empty` are not recorded
+fn has_recorded_fields(event: &Event<'_>) -> bool {
+    struct Visitor(bool);
+
+    impl Visit for Visitor {
+        fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {
+            self.0 = true;
+        }
+    }
+
+    let mut visitor = Visitor(false);
+    event.record(&mut visitor);
+    visitor.0
+}
+
+// Whether any string field of the event holds a replacement character, see `LossyUtf8`
+fn has_lossy_field(event: &Event<'_>) -> bool {
+    collect_fields(event).values().any(|value| {
+        value
+            .as_str()
+            .is_some_and(|value| value.contains('\u{FFFD}'))
+    })
+}
+
+/// Records bytes which should be, but might not be valid UTF-8 (e.g. from an FFI boundary) as
+/// a string, with invalid sequences replaced by U+FFFD instead of failing:
+/// `info!(name = ?LossyUtf8(bytes))`.
+pub struct LossyUtf8<'a>(pub &'a [u8]);
+
+impl std::fmt::Debug for LossyUtf8<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&String::from_utf8_lossy(self.0))
+    }
+}
+
+// Whether any field of the event was recorded through `Visit::record_error`
+fn has_error_field(event: &Event<'_>) -> bool {
+    struct Visitor(bool);
+
+    impl Visit for Visitor {
+        fn record_error(&mut self, _field: &Field, _value: &(dyn std::error::Error + 'static)) {
+            self.0 = true;
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+    }
+
+    let mut visitor = Visitor(false);
+    event.record(&mut visitor);
+    visitor.0
+}
+
+// The messages of the first error field and its sources, outermost first
+fn error_stack(event: &Event<'_>) -> Option<Vec<String>> {
+    struct Visitor(Option<Vec<String>>);
+
+    impl Visit for Visitor {
+        fn record_error(&mut self, _field: &Field, value: &(dyn std::error::Error + 'static)) {
+            if self.0.is_none() {
+                let chain = std::iter::successors(Some(value), |error| error.source());
+                self.0 = Some(chain.map(ToString::to_string).collect());
+            }
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+    }
+
+    let mut visitor = Visitor(None);
+    event.record(&mut visitor);
+    visitor.0
+}
+
+fn escalate(level: Level) -> Level {
+    match level {
+        Level::TRACE => Level::DEBUG,
+        Level::DEBUG => Level::INFO,
+        Level::INFO => Level::WARN,
+        _ => Level::ERROR,
+    }
+}
+
+struct HostInfo {
+    name: Option<String>,
+    pid: u32,
+}
+
+// Neither the hostname nor the pid change during runtime, so compute them only once
+fn host_info() -> &'static HostInfo {
+    static HOST_INFO: OnceLock<HostInfo> = OnceLock::new();
+    HOST_INFO.get_or_init(|| HostInfo {
+        name: hostname::get()
+            .ok()
+            .and_then(|name| name.into_string().ok())
+            .or_else(|| std::env::var("HOSTNAME").ok()),
+        pid: std::process::id(),
+    })
+}
+
+fn baggage_entries(baggage: &Baggage) -> serde_json::Map<String, serde_json::Value> {
+    baggage
+        .iter()
+        .map(|(key, (value, _))| (key.to_string(), value.to_string().into()))
+        .collect()
+}
+
+fn resource_fields(resource: &Resource) -> serde_json::Map<String, serde_json::Value> {
+    resource
+        .iter()
+        .map(|(key, value)| (key.to_string(), otel_value(value)))
+        .collect()
+}
+
+fn otel_value(value: &OtelValue) -> serde_json::Value {
+    match value {
+        OtelValue::Bool(value) => (*value).into(),
+        OtelValue::I64(value) => (*value).into(),
+        OtelValue::F64(value) => (*value).into(),
+        OtelValue::String(value) => value.as_str().into(),
+        OtelValue::Array(Array::Bool(values)) => values.clone().into(),
+        OtelValue::Array(Array::I64(values)) => values.clone().into(),
+        OtelValue::Array(Array::F64(values)) => values.clone().into(),
+        OtelValue::Array(Array::String(values)) => {
+            values.iter().map(|value| value.as_str()).collect()
+        }
+    }
+}
+
+struct BuildInfo {
+    commit: Option<String>,
+    time: Option<String>,
+}
+
+// The build is described by the deployment's environment, read once at the first event
+fn build_info() -> &'static BuildInfo {
+    static BUILD_INFO: OnceLock<BuildInfo> = OnceLock::new();
+    BUILD_INFO.get_or_init(|| {
+        let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+        BuildInfo {
+            commit: var("GIT_SHA").or_else(|| var("SOURCE_COMMIT")),
+            time: var("BUILD_TIME"),
+        }
+    })
+}
+
+// Compact or pretty output, chosen at runtime so both share one serializer type. The pretty
+// output doesn't end with a newline either, so each event is still terminated by exactly one.
+enum LineFormatter {
+    Compact(CompactFormatter),
+    Pretty(PrettyFormatter<'static>),
+}
+
+impl LineFormatter {
+    fn new(pretty: bool) -> Self {
+        match pretty {
+            true => Self::Pretty(PrettyFormatter::new()),
+            false => Self::Compact(CompactFormatter),
+        }
+    }
+}
+
+macro_rules! delegate_formatter {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        impl Formatter for LineFormatter {
+            $(
+                fn $method<W: ?Sized + io::Write>(
+                    &mut self,
+                    writer: &mut W,
+                    $($arg: $ty),*
+                ) -> io::Result<()> {
+                    match self {
+                        Self::Compact(formatter) => formatter.$method(writer, $($arg),*),
+                        Self::Pretty(formatter) => formatter.$method(writer, $($arg),*),
+                    }
+                }
+            )*
+        }
+    };
+}
+
+// the methods the `PrettyFormatter` overrides
+delegate_formatter! {
+    begin_array();
+    end_array();
+    begin_array_value(first: bool);
+    end_array_value();
+    begin_object();
+    end_object();
+    begin_object_key(first: bool);
+    begin_object_value();
+    end_object_value();
+}
+
 pub struct WriteAdaptor<'a> {
     fmt_write: &'a mut dyn std::fmt::Write,
 }
@@ -87,11 +2246,27 @@ impl<'a> io::Write for WriteAdaptor<'a> {
         let s =
             std::str::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
-        self.fmt_write
-            .write_str(s)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        self.fmt_write.write_str(s).map_err(io::Error::other)?;
+
+        // `write_str` either writes the whole string or fails, so the entire buffer is consumed
+        Ok(buf.len())
+    }
 
-        Ok(s.as_bytes().len())
+    fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                Ok(written) => buf = &buf[written..],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -99,8 +2274,86 @@ impl<'a> io::Write for WriteAdaptor<'a> {
     }
 }
 
+// The span object with the ids placed in it, see `IdPlacement`
+#[derive(serde::Serialize)]
+struct SpanWithIds<'a, S> {
+    #[serde(flatten)]
+    span: S,
+    #[serde(flatten)]
+    ids: Option<&'a BTreeMap<&'static str, String>>,
+}
+
+// The span and all its parents, ordered from the root to the span
+struct SerializableSpanList<'a, 'b, Span, N>(
+    &'b SpanRef<'a, Span>,
+    PhantomData<N>,
+    Option<u32>,
+    Option<&'b TraceIdHasher>,
+)
+where
+    Span: for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static;
+
+impl<'a, 'b, Span, N> Serialize for SerializableSpanList<'a, 'b, Span, N>
+where
+    Span: for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn serialize<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
+    where
+        Ser: serde::ser::Serializer,
+    {
+        use serde::ser::SerializeSeq;
+
+        let mut serializer = serializer.serialize_seq(None)?;
+        for span in self.0.scope().from_root() {
+            serializer.serialize_element(&SerializableSpan(&span, self.1, self.2, self.3))?;
+        }
+        serializer.end()
+    }
+}
+
 // https://github.com/tokio-rs/tracing/blob/4e65750b13721fee7a7ac05b053e1b9c3d21244f/tracing-subscriber/src/fmt/format/json.rs#L110
-struct SerializableSpan<'a, 'b, Span, N>(&'b SpanRef<'a, Span>, PhantomData<N>)
+// The `FormattedFields` of a span parsed once and kept in its extensions, so a long-lived span
+// isn't re-parsed for each of its events. `source` is the formatted string it was parsed from,
+// fields recorded later on change it and cause a re-parse.
+//
+// This resolves the upstream TODO of re-parsing the fields, see
+// https://github.com/tokio-rs/tracing/issues/391.
+struct ParsedSpanFields {
+    source: String,
+    fields: Result<serde_json::Value, String>,
+}
+
+impl ParsedSpanFields {
+    fn parse(source: &str) -> Self {
+        Self {
+            source: source.to_owned(),
+            fields: serde_json::from_str(source).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// The keys `SerializableSpan` emits itself, span fields named like one are emitted as
+// `fields.{name}`
+const SPAN_KEYS: &[&str] = &[
+    "name",
+    "linked_trace_ids",
+    "dropped_attributes",
+    "busy_ms",
+    "idle_ms",
+    // flattened in by `SpanWithIds` with `IdPlacement::Span` or `Both`
+    "span_id",
+    "trace_id",
+];
+
+// The `linked_trace_ids` are transformed by the `TraceIdHasher`, like the emitted `trace_id`
+struct SerializableSpan<'a, 'b, Span, N>(
+    &'b SpanRef<'a, Span>,
+    PhantomData<N>,
+    Option<u32>,
+    Option<&'b TraceIdHasher>,
+)
 where
     Span: for<'lookup> LookupSpan<'lookup>,
     N: for<'writer> FormatFields<'writer> + 'static;
@@ -115,53 +2368,474 @@ where
         Ser: serde::ser::Serializer,
     {
         let mut serializer = serializer.serialize_map(None)?;
+        serializer.serialize_entry("name", self.0.metadata().name())?;
 
-        let ext = self.0.extensions();
+        let mut ext = self.0.extensions();
         let data = ext
             .get::<FormattedFields<N>>()
             .expect("Unable to find FormattedFields in extensions; this is a bug");
 
-        // TODO: let's _not_ do this, but this resolves
-        // https://github.com/tokio-rs/tracing/issues/391.
-        // We should probably rework this to use a `serde_json::Value` or something
-        // similar in a JSON-specific layer, but I'd (david)
-        // rather have a uglier fix now rather than shipping broken JSON.
-        match serde_json::from_str::<serde_json::Value>(data) {
-            Ok(serde_json::Value::Object(fields)) => {
-                for field in fields {
-                    serializer.serialize_entry(&field.0, &field.1)?;
-                }
-            }
-            // We have fields for this span which are valid JSON but not an object.
-            // This is probably a bug, so panic if we're in debug mode
-            Ok(_) if cfg!(debug_assertions) => panic!(
-                "span '{}' had malformed fields! this is a bug.\n  error: invalid JSON object\n  fields: {:?}",
-                self.0.metadata().name(),
-                data
-            ),
-            // If we *aren't* in debug mode, it's probably best not to
-            // crash the program, let's log the field found but also an
-            // message saying it's type  is invalid
-            Ok(value) => {
-                serializer.serialize_entry("field", &value)?;
-                serializer.serialize_entry("field_error", "field was no a valid object")?
-            }
-            // We have previously recorded fields for this span
-            // should be valid JSON. However, they appear to *not*
-            // be valid JSON. This is almost certainly a bug, so
-            // panic if we're in debug mode
-            Err(e) if cfg!(debug_assertions) => panic!(
-                "span '{}' had malformed fields! this is a bug.\n  error: {}\n  fields: {:?}",
-                self.0.metadata().name(),
-                e,
-                data
-            ),
-            // If we *aren't* in debug mode, it's probably best not
-            // crash the program, but let's at least make sure it's clear
-            // that the fields are not supposed to be missing.
-            Err(e) => serializer.serialize_entry("field_error", &format!("{e}"))?,
-        };
-        serializer.serialize_entry("name", self.0.metadata().name())?;
+        // spans without recorded fields are formatted as `{}` or an empty string, depending on
+        // the `FormatFields` implementation, so there's nothing to parse
+        if !matches!(data.as_str(), "" | "{}") {
+            let is_cached = ext
+                .get::<ParsedSpanFields>()
+                .is_some_and(|parsed| parsed.source == data.as_str());
+            if !is_cached {
+                let parsed = ParsedSpanFields::parse(data);
+                drop(ext);
+                self.0.extensions_mut().replace(parsed);
+                ext = self.0.extensions();
+            }
+            let parsed = ext
+                .get::<ParsedSpanFields>()
+                .expect("parsed span fields were just inserted");
+
+            match &parsed.fields {
+                Ok(serde_json::Value::Object(fields)) => {
+                    for (field, value) in fields {
+                        match SPAN_KEYS.contains(&field.as_str()) {
+                            true => serializer.serialize_entry(&format!("fields.{field}"), value)?,
+                            false => serializer.serialize_entry(field, value)?,
+                        }
+                    }
+                }
+                // We have fields for this span which are valid JSON but not an object.
+                // This is probably a bug, so panic if we're in debug mode
+                Ok(_) if cfg!(debug_assertions) => panic!(
+                    "span '{}' had malformed fields! this is a bug.\n  error: invalid JSON object\n  fields: {:?}",
+                    self.0.metadata().name(),
+                    parsed.source
+                ),
+                // If we *aren't* in debug mode, it's probably best not to
+                // crash the program, let's log the field found but also an
+                // message saying it's type  is invalid
+                Ok(value) => {
+                    serializer.serialize_entry("field", value)?;
+                    serializer.serialize_entry("field_error", "field was no a valid object")?
+                }
+                // We have previously recorded fields for this span
+                // should be valid JSON. However, they appear to *not*
+                // be valid JSON. This is almost certainly a bug, so
+                // panic if we're in debug mode
+                Err(e) if cfg!(debug_assertions) => panic!(
+                    "span '{}' had malformed fields! this is a bug.\n  error: {}\n  fields: {:?}",
+                    self.0.metadata().name(),
+                    e,
+                    parsed.source
+                ),
+                // If we *aren't* in debug mode, it's probably best not
+                // crash the program, but let's at least make sure it's clear
+                // that the fields are not supposed to be missing.
+                Err(e) => serializer.serialize_entry("field_error", e)?,
+            }
+        }
+
+        if let Some(otel_data) = ext.get::<OtelData>() {
+            let mut linked_trace_ids = linked_trace_ids(otel_data);
+            if let Some(hasher) = self.3 {
+                for linked_trace_id in &mut linked_trace_ids {
+                    *linked_trace_id = hasher(linked_trace_id);
+                }
+            }
+            if !linked_trace_ids.is_empty() {
+                serializer.serialize_entry("linked_trace_ids", &linked_trace_ids)?;
+            }
+
+            // the builder holds all attributes, the limit is only applied once the span is built
+            let attributes = otel_data.builder.attributes.as_ref().map_or(0, |a| a.len());
+            let dropped_attributes = self
+                .2
+                .map_or(0, |limit| attributes.saturating_sub(limit as usize));
+            if dropped_attributes > 0 {
+                serializer.serialize_entry("dropped_attributes", &dropped_attributes)?;
+            }
+        }
+
+        if let Some(timing) = ext.get::<SpanTiming>() {
+            let (busy, idle) = timing.busy_idle();
+            serializer.serialize_entry("busy_ms", &(busy.as_secs_f64() * 1000.0))?;
+            serializer.serialize_entry("idle_ms", &(idle.as_secs_f64() * 1000.0))?;
+        }
+
         serializer.end()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use opentelemetry::{
+        sdk::trace::TracerProvider,
+        trace::{SpanContext, TraceId, TraceState, TracerProvider as _},
+    };
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt};
+
+    use super::*;
+    use crate::trace::{remote_trace_span, RemoteTraceContext};
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    fn remote_span() -> tracing::Span {
+        let remote = RemoteTraceContext {
+            info: TraceInfo {
+                trace_id: TRACE_ID.into(),
+                span_id: SPAN_ID.into(),
+            },
+            trace_flags: TraceFlags::SAMPLED,
+            ..Default::default()
+        };
+        remote_trace_span(tracing::info_span!("request"), &remote).unwrap()
+    }
+
+    fn hasher() -> TraceIdHasher {
+        Arc::new(|id| format!("hashed-{}", id.len()))
+    }
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MakeWriter<'_> for Output {
+        type Writer = Self;
+
+        fn make_writer(&self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    // The lines `json` formats for the events of `f`, the OpenTelemetry layer has no exporters
+    fn format(json: Json, f: impl FnOnce()) -> Vec<serde_json::Value> {
+        let output = Output::default();
+        let provider = TracerProvider::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .event_format(json)
+                    .with_writer(output.clone()),
+            );
+        tracing::subscriber::with_default(subscriber, f);
+
+        let output = output.0.lock().unwrap();
+        String::from_utf8_lossy(&output)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn the_unit_like_entry_point_still_compiles() {
+        let lines = format(Json, || tracing::info!(answer = 42, "hello"));
+        assert_eq!(lines[0]["fields"]["message"], "hello");
+        assert_eq!(lines[0]["fields"]["answer"], 42);
+        assert_eq!(lines[0]["level"], "INFO");
+    }
+
+    #[test]
+    fn dedup_collapses_identical_lines() {
+        let json = Json::default().dedup_window(Some(Duration::from_secs(60)));
+        let lines = format(json, || {
+            for _ in 0..3 {
+                tracing::info!("repeated");
+            }
+            tracing::info!("different");
+        });
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["fields"]["message"], "repeated");
+        assert_eq!(lines[1]["repeated"], 2);
+        assert_eq!(lines[2]["fields"]["message"], "different");
+    }
+
+    #[test]
+    fn dedup_flush_emits_the_pending_count() {
+        let json = Json::default().dedup_window(Some(Duration::from_secs(60)));
+        let lines = format(json.clone(), || {
+            tracing::info!("repeated");
+            tracing::info!("repeated");
+        });
+        assert_eq!(lines.len(), 1);
+
+        let mut flushed = Vec::new();
+        json.flush_dedup(&mut flushed).unwrap();
+        let summary: serde_json::Value = serde_json::from_slice(&flushed).unwrap();
+        assert_eq!(summary["repeated"], 1);
+    }
+
+    #[test]
+    fn sequence_numbers_increase() {
+        let lines = format(Json::default().with_sequence(true), || {
+            tracing::info!("first");
+            tracing::info!("second");
+        });
+
+        let (first, second) = (lines[0]["seq"].as_u64(), lines[1]["seq"].as_u64());
+        assert!(first.unwrap() < second.unwrap());
+    }
+
+    #[test]
+    fn sequence_numbers_do_not_defeat_dedup() {
+        for pretty in [false, true] {
+            let json = Json::default()
+                .with_sequence(true)
+                .pretty(pretty)
+                .dedup_window(Some(Duration::from_secs(60)));
+            let output = Output::default();
+            let subscriber = tracing_subscriber::registry().with(
+                tracing_subscriber::fmt::layer()
+                    .event_format(json.clone())
+                    .with_writer(output.clone()),
+            );
+            tracing::subscriber::with_default(subscriber, || {
+                for _ in 0..3 {
+                    tracing::info!("repeated");
+                }
+            });
+            json.flush_dedup(&mut output.clone()).unwrap();
+
+            let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+            let lines: Vec<serde_json::Value> = serde_json::Deserializer::from_str(&output)
+                .into_iter()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(lines.len(), 2, "pretty: {pretty}");
+            assert_eq!(lines[1]["repeated"], 2);
+        }
+    }
+
+    #[test]
+    fn ids_of_remote_spans_are_emitted() {
+        let lines = format(Json::default(), || {
+            remote_span().in_scope(|| tracing::info!("hello"));
+        });
+
+        assert_eq!(lines[0]["trace_id"], TRACE_ID);
+        assert_eq!(lines[0]["span"]["name"], "request");
+        assert_eq!(lines[0]["remote_parent_span_id"], SPAN_ID);
+    }
+
+    #[test]
+    fn hasher_transforms_the_ids_consistently() {
+        let json = Json::default().trace_id_hasher(Some(hasher()));
+        let lines = format(json, || {
+            let span = remote_span();
+            span.in_scope(|| tracing::info!("first"));
+            span.in_scope(|| tracing::info!("second"));
+        });
+
+        assert_eq!(lines[0]["trace_id"], "hashed-32");
+        assert_eq!(lines[0]["span_id"], "hashed-16");
+        assert_eq!(lines[0]["remote_parent_span_id"], "hashed-16");
+        assert_eq!(lines[0]["trace_id"], lines[1]["trace_id"]);
+        assert_eq!(lines[0]["span_id"], lines[1]["span_id"]);
+    }
+
+    #[test]
+    fn hasher_omits_the_entries_requiring_raw_ids() {
+        let json = Json::default()
+            .emit_traceparent(true)
+            .emit_xray(true)
+            .trace_id_hasher(Some(hasher()));
+        let lines = format(json, || {
+            let span = remote_span();
+            span.add_link(SpanContext::new(
+                TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap(),
+                SpanId::from_hex("1111111111111111").unwrap(),
+                TraceFlags::SAMPLED,
+                true,
+                TraceState::default(),
+            ));
+            span.in_scope(|| tracing::info!("hello"));
+        });
+
+        let line = lines[0].as_object().unwrap();
+        assert!(!line.contains_key("traceparent"));
+        assert!(!line.contains_key("AWS.XRAY.TraceId"));
+        assert!(!line.contains_key("AWS.XRAY.SegmentId"));
+        assert_eq!(
+            line["span"]["linked_trace_ids"],
+            serde_json::json!(["hashed-32"])
+        );
+        assert!(!lines[0].to_string().contains(TRACE_ID));
+    }
+
+    #[test]
+    fn raw_id_entries_are_emitted_without_a_hasher() {
+        let json = Json::default().emit_traceparent(true).emit_xray(true);
+        let lines = format(json, || {
+            remote_span().in_scope(|| tracing::info!("hello"));
+        });
+
+        let traceparent = lines[0]["traceparent"].as_str().unwrap();
+        assert!(traceparent.starts_with(&format!("00-{TRACE_ID}-")));
+        assert_eq!(
+            lines[0]["AWS.XRAY.TraceId"],
+            "1-4bf92f35-77b34da6a3ce929d0e0e4736"
+        );
+    }
+
+    fn clock(date_time: DateTime<Utc>) -> TimestampSource {
+        TimestampSource::Clock(Arc::new(move || date_time))
+    }
+
+    #[test]
+    fn timestamps_come_from_the_injected_clock() {
+        let now = DateTime::parse_from_rfc3339("2024-01-02T03:04:05.000000006Z").unwrap();
+        let now = now.with_timezone(&Utc);
+        let format_with = |timestamp_format| {
+            let json = Json::default()
+                .timestamp_source(clock(now))
+                .timestamp_format(timestamp_format);
+            format(json, || tracing::info!("hello")).remove(0)["timestamp"].clone()
+        };
+
+        assert_eq!(
+            format_with(TimestampFormat::Rfc3339),
+            "2024-01-02T03:04:05.000000006+00:00"
+        );
+        assert_eq!(
+            format_with(TimestampFormat::EpochMillis),
+            1_704_164_645_000i64
+        );
+        assert_eq!(
+            format_with(TimestampFormat::EpochNanos),
+            1_704_164_645_000_000_006i64
+        );
+    }
+
+    #[test]
+    fn epoch_nanos_out_of_range_do_not_panic() {
+        let far_future = DateTime::parse_from_rfc3339("3000-01-01T00:00:00Z").unwrap();
+        let json = Json::default()
+            .timestamp_source(clock(far_future.with_timezone(&Utc)))
+            .timestamp_format(TimestampFormat::EpochNanos);
+        let lines = format(json, || tracing::info!("hello"));
+        assert_eq!(lines[0]["timestamp"], "3000-01-01T00:00:00+00:00");
+    }
+
+    fn fallback_line(json: &Json) -> serde_json::Value {
+        let error = <serde_json::Error as serde::ser::Error>::custom("failed");
+        let timestamp = serde_json::Value::from("2024-01-02T03:04:05+00:00");
+        let line = json.fallback_line(&timestamp, Level::INFO, Some("broken".into()), &error);
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn fallback_lines_are_shaped_like_regular_ones() {
+        let json = Json::default()
+            .root_key(Some("log".into()))
+            .rename_keys([("level", "severity"), ("timestamp", "ts")].into());
+        let regular = format(json.clone(), || tracing::info!("fine")).remove(0);
+        let fallback = fallback_line(&json);
+
+        let (regular, fallback) = (&regular["log"], &fallback["log"]);
+        assert_eq!(fallback["fields"]["message"], "broken");
+        assert_eq!(fallback["severity"], regular["severity"]);
+        assert!(fallback["ts"].is_string() && regular["ts"].is_string());
+        assert_eq!(fallback["_format_error"], "failed");
+    }
+
+    #[test]
+    fn fallback_lines_follow_flatten_fields() {
+        let line = fallback_line(&Json::default().flatten_fields(true));
+        assert_eq!(line["message"], "broken");
+        assert_eq!(line["level"], "INFO");
+    }
+
+    #[test]
+    fn root_key_nests_the_whole_line() {
+        let json = Json::default().root_key(Some("log".into()));
+        let lines = format(json, || {
+            let span = remote_span();
+            let _entered = span.enter();
+            tracing::info!("hello");
+        });
+
+        let line = lines[0].as_object().unwrap();
+        assert_eq!(line.keys().collect::<Vec<_>>(), ["log"]);
+        assert_eq!(line["log"]["fields"]["message"], "hello");
+        assert_eq!(line["log"]["level"], "INFO");
+        assert_eq!(line["log"]["trace_id"], TRACE_ID);
+    }
+
+    #[test]
+    fn lines_are_not_nested_without_a_root_key() {
+        let lines = format(Json::default(), || tracing::info!("hello"));
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0].get("log"), None);
+    }
+
+    #[test]
+    fn flatten_fields_emits_fields_at_the_top_level() {
+        let json = Json::default().flatten_fields(true);
+        let lines = format(json, || tracing::info!(answer = 42, "hello"));
+
+        assert_eq!(lines[0]["message"], "hello");
+        assert_eq!(lines[0]["answer"], 42);
+        assert_eq!(lines[0].get("fields"), None);
+    }
+
+    #[test]
+    fn flattened_fields_never_override_the_formatters_entries() {
+        let json = Json::default()
+            .flatten_fields(true)
+            .rename_keys(HashMap::from([("trace_id", "tid")]));
+        let lines = format(json, || {
+            let span = remote_span();
+            let _entered = span.enter();
+            tracing::info!(level = "forged", tid = "forged", "hello");
+        });
+
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["fields.level"], "forged");
+        assert_eq!(lines[0]["tid"], TRACE_ID);
+        assert_eq!(lines[0]["fields.tid"], "forged");
+    }
+
+    #[test]
+    fn max_field_len_truncates_long_strings() {
+        let json = Json::default().max_field_len(Some(3));
+        let lines = format(json, || {
+            tracing::info!(long = "äöüß", short = "abc", number = 123_456, "hello")
+        });
+
+        let fields = &lines[0]["fields"];
+        assert_eq!(fields["long"], "äöü…");
+        assert_eq!(fields["long_truncated"], true);
+        assert_eq!(fields["message"], "hel…");
+        assert_eq!(fields["short"], "abc");
+        assert_eq!(fields.get("short_truncated"), None);
+        assert_eq!(fields["number"], 123_456);
+    }
+
+    #[test]
+    fn max_field_len_exempts_reserved_keys() {
+        let json = Json::default().flatten_fields(true).max_field_len(Some(3));
+        let lines = format(json, || tracing::info!(target = "abcdef", "hi"));
+
+        assert_eq!(lines[0]["fields.target"], "abcdef");
+        assert_eq!(lines[0].get("fields.target_truncated"), None);
+        assert_eq!(lines[0]["message"], "hi");
+    }
+
+    #[test]
+    fn fields_are_not_truncated_without_max_field_len() {
+        let long = "x".repeat(10_000);
+        let lines = format(Json::default(), || tracing::info!(long = %long));
+        assert_eq!(lines[0]["fields"]["long"], long.as_str());
+    }
+}
@@ -0,0 +1,15 @@
+pub mod export;
+pub mod filter;
+pub mod framing;
+#[cfg(feature = "reqwest")]
+pub mod jaeger;
+pub mod json;
+#[cfg(feature = "tower")]
+pub mod middleware;
+pub mod sampling;
+pub mod setup;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod text;
+pub mod timing;
+pub mod trace;
@@ -1,46 +1,50 @@
-mod json;
-mod trace;
+use std::time::Duration;
 
-use opentelemetry::{
-    global,
-    sdk::{self, resource::Resource, trace::Tracer},
-    trace::TracerProvider,
-    KeyValue,
+use opentelemetry::trace::TraceFlags;
+use tracing::{info, info_span, Instrument};
+use tracing_stable_trace_id_example::{
+    json, setup,
+    trace::{remote_trace_span, RemoteTraceContext, TraceInfo},
 };
-use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
-use opentelemetry_semantic_conventions::resource;
-use tracing::{info, info_span};
-use tracing_subscriber::{fmt, prelude::*};
-
-use crate::trace::{remote_trace_span, RemoteTraceContext, TraceInfo};
 
 #[tokio::main]
 async fn main() {
-    tracing::subscriber::set_global_default(
-        tracing_subscriber::registry()
-            .with(tracing_opentelemetry::layer().with_tracer(otel_tracer()))
-            .with(fmt::layer().json().event_format(json::Json)),
-    )
+    let tracer = setup::otel_tracer_from_env().unwrap();
+    tracing::subscriber::set_global_default(setup::build_filtered_subscriber(
+        setup::SubscriberConfig::new(Some(tracer), json::Json::new()),
+    ))
     .unwrap();
+    #[cfg(feature = "log")]
+    if let Err(err) = setup::init_log_bridge() {
+        eprintln!("{err}");
+    }
 
     let remote_trace_context = RemoteTraceContext {
         info: TraceInfo {
             trace_id: "9d96f6d506048d33796d850a09797e55".into(),
             span_id: "0db1818f6e5514ee".into(),
         },
-        trace_flags: 0,
+        trace_flags: TraceFlags::default(),
+        trace_state: None,
+        meta: None,
     };
 
-    let span = remote_trace_span(info_span!("main one"), &remote_trace_context);
-    let current_span = span.enter();
-
-    info!("main one");
-    nested();
-    nested_async().await;
-
-    drop(current_span);
-
-    global::shutdown_tracer_provider();
+    let span = remote_trace_span(info_span!("main one"), &remote_trace_context).unwrap();
+
+    // Holding a `span.enter()` guard across an `.await` loses the span as soon as the task
+    // yields or moves to another worker thread, so the remote trace id wouldn't reliably
+    // reach the spans created after it. Instrumenting the future re-enters it on every poll.
+    async {
+        info!("main one");
+        nested();
+        nested_async().await;
+    }
+    .instrument(span)
+    .await;
+
+    if let Err(err) = setup::shutdown_tracing(Duration::from_secs(5)) {
+        eprintln!("{err}");
+    }
 }
 
 #[tracing::instrument]
@@ -52,32 +56,3 @@ fn nested() {
 async fn nested_async() {
     info!("nested async");
 }
-
-fn otel_tracer() -> Tracer {
-    let exporter = opentelemetry_otlp::new_exporter()
-        .http()
-        .with_endpoint("http://localhost:4318/v1/traces");
-
-    let span_exporter = SpanExporterBuilder::from(exporter)
-        .build_span_exporter()
-        .unwrap();
-
-    let batch_processor =
-        sdk::trace::BatchSpanProcessor::builder(span_exporter, opentelemetry::runtime::Tokio)
-            .build();
-
-    let trace_config = sdk::trace::config().with_resource(Resource::new(vec![KeyValue::new(
-        resource::SERVICE_NAME,
-        "tracing-stable-trace-id-example",
-    )]));
-
-    let provider = sdk::trace::TracerProvider::builder()
-        .with_span_processor(batch_processor)
-        .with_config(trace_config)
-        .build();
-
-    let tracer = provider.tracer("opentelemetry-otlp");
-
-    global::set_tracer_provider(provider);
-    tracer
-}
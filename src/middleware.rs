@@ -0,0 +1,52 @@
+use std::task::{Context, Poll};
+
+use tower::{Layer, Service};
+use tracing::{info_span, instrument::Instrumented, Instrument};
+
+use crate::trace::{remote_trace_span, RemoteTraceContext};
+
+// Tower layer wrapping each request in a `request` span with the `http.method` and `http.path`
+// fields. The span continues the trace of the `traceparent` (and `tracestate`) headers, or
+// starts a new trace if they are missing or malformed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TraceContextLayer;
+
+impl<S> Layer<S> for TraceContextLayer {
+    type Service = TraceContextService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceContextService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceContextService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<http::Request<B>> for TraceContextService<S>
+where
+    S: Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Instrumented<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let span = info_span!(
+            "request",
+            http.method = %request.method(),
+            http.path = request.uri().path(),
+        );
+        let span = match RemoteTraceContext::from_headers(request.headers()) {
+            Some(trace_context) => remote_trace_span(span.clone(), &trace_context).unwrap_or(span),
+            None => span,
+        };
+
+        span.in_scope(|| self.inner.call(request)).instrument(span)
+    }
+}
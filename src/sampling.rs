@@ -0,0 +1,90 @@
+use opentelemetry::{
+    sdk::{
+        trace::{Sampler, ShouldSample},
+        InstrumentationLibrary,
+    },
+    trace::{Link, OrderMap, SamplingDecision, SamplingResult, SpanKind, TraceContextExt, TraceId},
+    Context, Key, Value,
+};
+use tracing::Span;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::{registry::LookupSpan, Registry};
+
+/// The span attribute `ForceSampling` looks for, a span with it set to `true` is always sampled.
+pub const FORCE_SAMPLE_KEY: &str = "sampling.force";
+
+// Samples spans marked through `force_sample` (e.g. by middleware for requests of a user being
+// debugged) regardless of the wrapped sampler, which decides for all other spans. Wrap a
+// `ParentBased` sampler, so the children of a forced span follow its decision.
+#[derive(Debug, Clone)]
+pub struct ForceSampling<S> {
+    inner: S,
+}
+
+impl<S> ForceSampling<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S: ShouldSample + Clone + 'static> ShouldSample for ForceSampling<S> {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &OrderMap<Key, Value>,
+        links: &[Link],
+        instrumentation_library: &InstrumentationLibrary,
+    ) -> SamplingResult {
+        if attributes.get(&Key::from_static_str(FORCE_SAMPLE_KEY)) == Some(&Value::Bool(true)) {
+            let trace_state = parent_context
+                .map(|cx| cx.span().span_context().trace_state().clone())
+                .unwrap_or_default();
+            return SamplingResult {
+                decision: SamplingDecision::RecordAndSample,
+                attributes: Vec::new(),
+                trace_state,
+            };
+        }
+
+        self.inner.should_sample(
+            parent_context,
+            trace_id,
+            name,
+            span_kind,
+            attributes,
+            links,
+            instrumentation_library,
+        )
+    }
+}
+
+// Sample `ratio` of the traces starting here, while continuing the decision of a (remote)
+// parent, so a trace sampled upstream is never dropped locally. Spans marked through
+// `force_sample` are always sampled.
+pub fn ratio_sampler(ratio: f64) -> ForceSampling<Sampler> {
+    ForceSampling::new(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+        ratio,
+    ))))
+}
+
+// Mark `span` to be sampled by `ForceSampling`. Has to happen before the span's trace id is
+// first used, e.g. by creating a child span, since the sampling decision isn't revisited after.
+// Returns false if the span isn't recorded by the OpenTelemetry layer of a `Registry`.
+pub fn force_sample(span: &Span) -> bool {
+    span.with_subscriber(|(id, dispatch)| {
+        let span = dispatch.downcast_ref::<Registry>()?.span(id)?;
+        let mut extensions = span.extensions_mut();
+        let otel_data = extensions.get_mut::<OtelData>()?;
+        otel_data
+            .builder
+            .attributes
+            .get_or_insert_with(OrderMap::default)
+            .insert(Key::from_static_str(FORCE_SAMPLE_KEY), Value::Bool(true));
+        Some(())
+    })
+    .flatten()
+    .is_some()
+}
@@ -0,0 +1,881 @@
+use std::{
+    any::Any,
+    borrow::Cow,
+    env,
+    error::Error,
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex, PoisonError, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use opentelemetry::{
+    global,
+    metrics::{Meter, MeterProvider as _, MetricsError},
+    propagation::TextMapPropagator,
+    sdk::{
+        self,
+        export::metrics::aggregation::cumulative_temporality_selector,
+        metrics::{controllers::BasicController, selectors},
+        propagation::{BaggagePropagator, TextMapCompositePropagator, TraceContextPropagator},
+        resource::Resource,
+        trace::{Tracer, TracerProvider},
+    },
+    trace::{TraceError, TraceFlags, TracerProvider as _},
+    KeyValue,
+};
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use opentelemetry_semantic_conventions::resource;
+
+use tracing::{Dispatch, Level, Metadata, Subscriber};
+use tracing_opentelemetry::{OtelData, PreSampledTracer};
+use tracing_subscriber::{
+    filter::{filter_fn, LevelFilter},
+    fmt::{self, MakeWriter},
+    prelude::*,
+    registry::LookupSpan,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::{
+    export::{QueueDepth, RetryPolicy, RetryingSpanExporter},
+    json::Json,
+    sampling::ratio_sampler,
+    trace::{remote_trace_span, RemoteTraceContext, TraceInfo},
+};
+
+// Route the `Json` output by level: ERROR and WARN events go to `err_writer`, all others to
+// `out_writer`. Built from two filtered fmt layers sharing the same formatter, so trace
+// correlation is identical on both streams.
+pub fn split_by_level<S, Out, Err>(json: Json, out_writer: Out, err_writer: Err) -> impl Layer<S>
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    Out: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+    Err: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    // spans have to pass both filters, otherwise their fields aren't recorded for the output
+    let is_err = |meta: &Metadata<'_>| meta.is_span() || *meta.level() <= Level::WARN;
+    let is_out = |meta: &Metadata<'_>| meta.is_span() || *meta.level() > Level::WARN;
+
+    fmt::layer()
+        .json()
+        .event_format(json.clone())
+        .with_writer(err_writer)
+        .with_filter(filter_fn(is_err))
+        .and_then(
+            fmt::layer()
+                .json()
+                .event_format(json)
+                .with_writer(out_writer)
+                .with_filter(filter_fn(is_out)),
+        )
+}
+
+// Forward the records of the `log` crate to the `tracing` subscriber, so the ones of
+// dependencies not using `tracing` are formatted with the ids of the current span as well.
+// Fails if a logger is installed already.
+#[cfg(feature = "log")]
+pub fn init_log_bridge() -> Result<(), tracing_log::log::SetLoggerError> {
+    tracing_log::LogTracer::init()
+}
+
+static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+
+// Install the W3C trace context and baggage propagators as the global text map propagator, used
+// by `trace::extract_remote_context`
+pub fn init_propagator() {
+    let propagators: Vec<Box<dyn TextMapPropagator + Send + Sync>> = vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(BaggagePropagator::new()),
+    ];
+    global::set_text_map_propagator(TextMapCompositePropagator::new(propagators));
+}
+
+// Flush and shut down the global tracer provider like `global::shutdown_tracer_provider`, and
+// remember it, so the `Json` output of events emitted during teardown can tell the missing
+// ids apart from spans that were never traced.
+pub fn shutdown_tracer_provider() {
+    SHUT_DOWN.store(true, Ordering::Relaxed);
+    global::shutdown_tracer_provider();
+}
+
+pub fn is_shut_down() -> bool {
+    SHUT_DOWN.load(Ordering::Relaxed)
+}
+
+// The provider installed through `set_tracer_provider`, the global one can't be flushed
+static INSTALLED_PROVIDER: Mutex<Option<TracerProvider>> = Mutex::new(None);
+
+// Install `provider` as the global tracer provider like `global::set_tracer_provider`, keeping
+// a handle so `shutdown_tracing` can flush it
+pub fn set_tracer_provider(provider: TracerProvider) {
+    let mut installed = INSTALLED_PROVIDER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    *installed = Some(provider.clone());
+    global::set_tracer_provider(provider);
+}
+
+#[derive(Debug)]
+pub enum ShutdownError {
+    /// Flushing the pending spans didn't complete within the timeout.
+    Timeout(Duration),
+    /// A span processor failed to flush, e.g. because the export failed.
+    Flush(TraceError),
+}
+
+impl std::fmt::Display for ShutdownError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout(timeout) => write!(f, "flushing spans timed out after {timeout:?}"),
+            Self::Flush(err) => write!(f, "failed to flush spans: {err}"),
+        }
+    }
+}
+
+impl Error for ShutdownError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Timeout(_) => None,
+            Self::Flush(err) => Some(err),
+        }
+    }
+}
+
+// Flush the pending spans of the provider installed through `set_tracer_provider` (e.g. by
+// `OtelTracerBuilder::install`) and shut it down, for short-lived processes exiting right
+// after. The lines held back by `Json::dedup_window` are flushed first. Waits at most `timeout`
+// for the flush, the provider is left installed if it doesn't complete in time. When called
+// within a Tokio runtime, it has to be a multi-threaded one, as the batch span processor needs
+// another worker to make progress meanwhile.
+pub fn shutdown_tracing(timeout: Duration) -> Result<(), ShutdownError> {
+    flush_dedup();
+
+    // only taken once flushed, so a retry after a timeout still finds the provider
+    let provider = INSTALLED_PROVIDER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    let Some(provider) = provider else {
+        shutdown_tracer_provider();
+        return Ok(());
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(provider.force_flush());
+    });
+    let results = receiver
+        .recv_timeout(timeout)
+        .map_err(|_| ShutdownError::Timeout(timeout))?;
+
+    INSTALLED_PROVIDER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    shutdown_tracer_provider();
+    match results.into_iter().find_map(Result::err) {
+        Some(err) => Err(ShutdownError::Flush(err)),
+        None => Ok(()),
+    }
+}
+
+/// What `build_subscriber` assembles: the OpenTelemetry layer (if a tracer is given) and the
+/// `Json` output written to `writer`.
+pub struct SubscriberConfig<T, W = fn() -> io::Stdout> {
+    pub tracer: Option<T>,
+    pub json: Json,
+    pub writer: W,
+}
+
+impl<T> SubscriberConfig<T> {
+    /// Writes to stdout. `json` can also be an unfinished `JsonBuilder`.
+    pub fn new(tracer: Option<T>, json: impl Into<Json>) -> Self {
+        Self {
+            tracer,
+            json: json.into(),
+            writer: io::stdout,
+        }
+    }
+}
+
+impl<T, W> SubscriberConfig<T, W> {
+    pub fn with_writer<W2>(self, writer: W2) -> SubscriberConfig<T, W2> {
+        SubscriberConfig {
+            tracer: self.tracer,
+            json: self.json,
+            writer,
+        }
+    }
+}
+
+// Assemble the registry with the OpenTelemetry and `Json` layers without installing it, for
+// applications managing their global subscriber themselves.
+pub fn build_subscriber<T, W>(
+    config: SubscriberConfig<T, W>,
+) -> impl Subscriber + for<'lookup> LookupSpan<'lookup> + Send + Sync
+where
+    T: opentelemetry::trace::Tracer + PreSampledTracer + Send + Sync + 'static,
+    T::Span: Send + Sync,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let otel_layer = config
+        .tracer
+        .map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+
+    let writer = SharedWriter(Arc::new(config.writer));
+    if config.json.has_dedup() {
+        let (json, writer) = (config.json.clone(), Arc::downgrade(&writer.0));
+        let mut flushes = DEDUP_FLUSHES.lock().unwrap_or_else(PoisonError::into_inner);
+        flushes.retain(DedupFlush::is_alive);
+        flushes.push(DedupFlush {
+            alive: writer.clone(),
+            flush: Box::new(move || {
+                if let Some(writer) = writer.upgrade() {
+                    let _ = json.flush_dedup(&mut writer.make_writer());
+                }
+            }),
+        });
+    }
+
+    Registry::default().with(otel_layer).with(
+        fmt::layer()
+            .json()
+            .event_format(config.json)
+            .with_writer(writer),
+    )
+}
+
+// Flushes the `Json::dedup_window` state of a subscriber assembled by `build_subscriber`. Only
+// a weak reference to its writer is kept, so the entry is removed once the subscriber is dropped.
+struct DedupFlush {
+    alive: Weak<dyn Any + Send + Sync>,
+    flush: Box<dyn Fn() + Send>,
+}
+
+impl DedupFlush {
+    fn is_alive(&self) -> bool {
+        self.alive.strong_count() > 0
+    }
+}
+
+static DEDUP_FLUSHES: Mutex<Vec<DedupFlush>> = Mutex::new(Vec::new());
+
+fn flush_dedup() {
+    let mut flushes = DEDUP_FLUSHES.lock().unwrap_or_else(PoisonError::into_inner);
+    flushes.retain(DedupFlush::is_alive);
+    for flush in flushes.iter() {
+        (flush.flush)();
+    }
+}
+
+// Lets `build_subscriber` keep writing to the subscriber's writer for `flush_dedup`
+struct SharedWriter<W>(Arc<W>);
+
+impl<W> Clone for SharedWriter<W> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<'a, W: MakeWriter<'a>> MakeWriter<'a> for SharedWriter<W> {
+    type Writer = W::Writer;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.0.make_writer()
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.0.make_writer_for(meta)
+    }
+}
+
+// Like `build_subscriber`, with the levels filtered through `RUST_LOG`
+pub fn build_filtered_subscriber<T, W>(
+    config: SubscriberConfig<T, W>,
+) -> impl Subscriber + for<'lookup> LookupSpan<'lookup> + Send + Sync
+where
+    T: opentelemetry::trace::Tracer + PreSampledTracer + Send + Sync + 'static,
+    T::Span: Send + Sync,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    build_subscriber(config).with(env_filter())
+}
+
+// The directives of `RUST_LOG`, `info` when it's unset. Invalid directives are skipped.
+pub fn env_filter() -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy()
+}
+
+/// The transport of the OTLP span exporter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    /// Protobuf over HTTP, usually on port 4318.
+    #[default]
+    Http,
+    /// gRPC via tonic, usually on port 4317.
+    Grpc,
+}
+
+impl OtlpProtocol {
+    /// The collector on localhost at the protocol's default port.
+    pub fn default_endpoint(self) -> &'static str {
+        match self {
+            Self::Http => "http://localhost:4318/v1/traces",
+            Self::Grpc => "http://localhost:4317",
+        }
+    }
+}
+
+// Installs a tracer provider exporting via OTLP as the global one, and returns a tracer of it
+// for the OpenTelemetry layer. Spans are batched and exports retried with the default policy,
+// whichever protocol is used.
+#[derive(Debug, Clone)]
+pub struct OtelTracerBuilder {
+    endpoint: String,
+    service_name: String,
+    protocol: OtlpProtocol,
+    sampling_ratio: Option<f64>,
+}
+
+impl OtelTracerBuilder {
+    /// For `OtlpProtocol::Http`, `endpoint` is the full traces URL like
+    /// `http://localhost:4318/v1/traces`.
+    pub fn new(endpoint: impl Into<String>, service_name: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            service_name: service_name.into(),
+            protocol: OtlpProtocol::default(),
+            sampling_ratio: None,
+        }
+    }
+
+    /// Configured by the standard `OTEL_EXPORTER_OTLP_PROTOCOL` (`grpc` or `http/protobuf`),
+    /// `OTEL_EXPORTER_OTLP_ENDPOINT` (the collector's base URL, `/v1/traces` is appended for
+    /// HTTP) and `OTEL_SERVICE_NAME` variables. Falls back to a collector on localhost and the
+    /// name of this crate. `OTEL_TRACES_SAMPLER=parentbased_traceidratio` with the ratio in
+    /// `OTEL_TRACES_SAMPLER_ARG` sets the `sampling_ratio`.
+    pub fn from_env() -> Self {
+        let var = |name| env::var(name).ok().filter(|value| !value.is_empty());
+
+        let protocol = match var("OTEL_EXPORTER_OTLP_PROTOCOL").as_deref() {
+            Some("grpc") => OtlpProtocol::Grpc,
+            _ => OtlpProtocol::Http,
+        };
+        let endpoint = match (var("OTEL_EXPORTER_OTLP_ENDPOINT"), protocol) {
+            (Some(endpoint), OtlpProtocol::Http) => {
+                format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+            }
+            (Some(endpoint), OtlpProtocol::Grpc) => endpoint,
+            (None, protocol) => protocol.default_endpoint().to_owned(),
+        };
+        let service_name =
+            var("OTEL_SERVICE_NAME").unwrap_or_else(|| env!("CARGO_PKG_NAME").to_owned());
+
+        let sampling_ratio = match var("OTEL_TRACES_SAMPLER").as_deref() {
+            Some("parentbased_traceidratio") => var("OTEL_TRACES_SAMPLER_ARG")
+                .and_then(|ratio| ratio.parse().ok())
+                .or(Some(1.0)),
+            _ => None,
+        };
+
+        Self::new(endpoint, service_name)
+            .protocol(protocol)
+            .sampling_ratio(sampling_ratio)
+    }
+
+    pub fn protocol(mut self, protocol: OtlpProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sample only `ratio` of the traces started by this service, see
+    /// `sampling::ratio_sampler`. Traces continued from a sampled remote parent are always
+    /// sampled. All traces are sampled when `None`.
+    pub fn sampling_ratio(mut self, ratio: Option<f64>) -> Self {
+        self.sampling_ratio = ratio;
+        self
+    }
+
+    /// Has to be called within a Tokio runtime, which the batch span processor runs on.
+    pub fn install(self) -> Result<Tracer, TraceError> {
+        let span_exporter = match self.protocol {
+            OtlpProtocol::Http => SpanExporterBuilder::from(
+                opentelemetry_otlp::new_exporter()
+                    .http()
+                    .with_endpoint(&self.endpoint),
+            ),
+            OtlpProtocol::Grpc => SpanExporterBuilder::from(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(&self.endpoint),
+            ),
+        }
+        .build_span_exporter()?;
+        let span_exporter = RetryingSpanExporter::new(span_exporter, RetryPolicy::default());
+
+        let batch_processor =
+            sdk::trace::BatchSpanProcessor::builder(span_exporter, opentelemetry::runtime::Tokio)
+                .build();
+
+        let mut trace_config =
+            sdk::trace::config().with_resource(service_resource(self.service_name));
+        if let Some(ratio) = self.sampling_ratio {
+            trace_config = trace_config.with_sampler(ratio_sampler(ratio));
+        }
+
+        let provider = TracerProvider::builder()
+            .with_span_processor(batch_processor)
+            .with_config(trace_config)
+            .build();
+
+        let tracer = versioned_tracer(&provider, "opentelemetry-otlp", None);
+
+        set_tracer_provider(provider);
+        Ok(tracer)
+    }
+}
+
+// The resource describing this service: the attributes of the standard
+// `OTEL_RESOURCE_ATTRIBUTES` variable (comma-separated, URL-encoded `key=value` pairs) plus
+// `service_name`, which wins over a `service.name` given there.
+pub fn service_resource(service_name: impl Into<String>) -> Resource {
+    let from_env = env::var("OTEL_RESOURCE_ATTRIBUTES")
+        .map(|attributes| parse_resource_attributes(&attributes))
+        .unwrap_or_default();
+
+    Resource::new(from_env).merge(&Resource::new([KeyValue::new(
+        resource::SERVICE_NAME,
+        service_name.into(),
+    )]))
+}
+
+// Pairs without a `=` or with an empty key are skipped, empty values are kept.
+fn parse_resource_attributes(attributes: &str) -> Vec<KeyValue> {
+    attributes
+        .split(',')
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            let key = percent_decode(key.trim());
+            (!key.is_empty()).then(|| KeyValue::new(key, percent_decode(value.trim())))
+        })
+        .collect()
+}
+
+// Invalid escapes are kept as they are, invalid UTF-8 is replaced.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Install an OTLP/HTTP tracer provider exporting to the traces `endpoint`, see
+// `OtelTracerBuilder`
+pub fn otel_tracer(endpoint: &str, service_name: &str) -> Result<Tracer, TraceError> {
+    OtelTracerBuilder::new(endpoint, service_name).install()
+}
+
+// Install a tracer provider configured by the standard environment variables, see
+// `OtelTracerBuilder::from_env`
+pub fn otel_tracer_from_env() -> Result<Tracer, TraceError> {
+    OtelTracerBuilder::from_env().install()
+}
+
+// The controller installed through `otel_meter`, kept so `shutdown_metrics` can flush it
+static INSTALLED_METER_PROVIDER: Mutex<Option<BasicController>> = Mutex::new(None);
+
+// Install a meter provider pushing cumulative metrics to the OTLP/gRPC `endpoint` (the OTLP
+// metrics exporter only supports tonic) every `collect_period`, described by the same
+// `service_resource` as the spans of `otel_tracer`. Observable instruments like the one of
+// `observe_queue_depth` are sampled at that interval too. Has to be called within a Tokio
+// runtime, which the controller's worker runs on.
+pub fn otel_meter(
+    endpoint: &str,
+    service_name: &str,
+    collect_period: Duration,
+) -> Result<Meter, MetricsError> {
+    let controller = opentelemetry_otlp::new_pipeline()
+        .metrics(
+            selectors::simple::inexpensive(),
+            cumulative_temporality_selector(),
+            opentelemetry::runtime::Tokio,
+        )
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_resource(service_resource(service_name))
+        .with_period(collect_period)
+        .build()?;
+
+    let meter =
+        controller.versioned_meter("opentelemetry-otlp", Some(env!("CARGO_PKG_VERSION")), None);
+    *INSTALLED_METER_PROVIDER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner) = Some(controller);
+    Ok(meter)
+}
+
+// Export the metrics recorded since the last collection and stop the controller installed by
+// `otel_meter`. Blocks until the worker is done, so it has to be called from outside of a
+// current-thread Tokio runtime.
+pub fn shutdown_metrics() -> Result<(), MetricsError> {
+    let controller = INSTALLED_METER_PROVIDER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    match controller {
+        Some(controller) => controller.stop(&opentelemetry::Context::current()),
+        None => Ok(()),
+    }
+}
+
+// Build an OTLP/gRPC span exporter talking to a collector listening on the Unix domain socket
+// at `path`, e.g. a sidecar. The connection is established lazily on the first export, but
+// building the exporter has to happen within a Tokio runtime. Requires the `tonic` feature.
+#[cfg(all(unix, feature = "tonic"))]
+pub fn uds_span_exporter(
+    path: impl Into<std::path::PathBuf>,
+) -> Result<opentelemetry_otlp::SpanExporter, TraceError> {
+    use tokio::net::UnixStream;
+    use tonic::transport::{Endpoint, Uri};
+    use tower::service_fn;
+
+    let path = path.into();
+    // tonic requires an URI, which is ignored by the connector though
+    let channel = Endpoint::from_static("http://localhost")
+        .connect_with_connector_lazy(service_fn(move |_: Uri| UnixStream::connect(path.clone())));
+
+    SpanExporterBuilder::from(
+        opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_channel(channel),
+    )
+    .build_span_exporter()
+}
+
+// Create a tracer whose spans are reported with the instrumentation scope `name` and `version`,
+// so the backend shows which version emitted them. Defaults to the version of this crate.
+pub fn versioned_tracer(
+    provider: &TracerProvider,
+    name: impl Into<Cow<'static, str>>,
+    version: Option<&'static str>,
+) -> Tracer {
+    let version = version.unwrap_or(env!("CARGO_PKG_VERSION"));
+    provider.versioned_tracer(name, Some(version), None)
+}
+
+// Report the estimated queue depth of the batch span processor as the `otel.bsp.queue_size`
+// gauge, to alarm before it starts dropping spans. The gauge is sampled whenever the metrics
+// controller collects, so the sampling interval is its collect period, e.g. the one passed to
+// `otel_meter`.
+pub fn observe_queue_depth(meter: &Meter, depth: QueueDepth) -> Result<(), MetricsError> {
+    let gauge = meter
+        .u64_observable_gauge("otel.bsp.queue_size")
+        .with_description("Estimated number of spans waiting in the batch span processor's queue")
+        .try_init()?;
+    meter.register_callback(move |cx| gauge.observe(cx, depth.get() as u64, &[]))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelfCheckProblem {
+    /// The subscriber doesn't record the check's span, e.g. because its filter disables the
+    /// span or because it isn't built on a `Registry`.
+    SpanNotRecorded,
+    /// The span carries no `OtelData`, so there's no OpenTelemetry layer to record the ids.
+    MissingOtelLayer,
+    /// The `trace_id` the formatter resolves isn't the one of the remote context the span
+    /// continues.
+    TraceIdMismatch {
+        expected: String,
+        found: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelfCheckReport {
+    pub problems: Vec<SelfCheckProblem>,
+}
+
+impl SelfCheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Verify that `json` and the OpenTelemetry layer of `dispatch`, the application's subscriber,
+// agree on the ids: a span continuing a known remote context is created on `dispatch`, and the
+// `trace_id` `json` resolves within it has to be the remote one (after hashing, if configured).
+// The span's `OtelData` is removed before it closes, so the OpenTelemetry layer never exports it.
+pub fn self_check(dispatch: &Dispatch, json: &Json) -> SelfCheckReport {
+    let remote = RemoteTraceContext {
+        info: TraceInfo {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".into(),
+            span_id: "00f067aa0ba902b7".into(),
+        },
+        trace_flags: TraceFlags::SAMPLED,
+        trace_state: None,
+        meta: None,
+    };
+    let expected = json.hash_id(&remote.info.trace_id);
+
+    let problem = tracing::dispatcher::with_default(dispatch, || {
+        let span = remote_trace_span(tracing::info_span!("self_check"), &remote)
+            .expect("the self check's remote context is valid");
+
+        let checked = span.in_scope(|| {
+            span.with_subscriber(|(id, dispatch)| {
+                let span = dispatch.downcast_ref::<Registry>()?.span(id)?;
+                if span.extensions().get::<OtelData>().is_none() {
+                    return Some(Err(SelfCheckProblem::MissingOtelLayer));
+                }
+
+                let found = json.trace_info(Some(&span));
+                // keeps the OpenTelemetry layer from exporting the span once it closes
+                span.extensions_mut().remove::<OtelData>();
+                Some(Ok(found))
+            })
+        });
+
+        let found = match checked.flatten() {
+            Some(Ok(found)) => found.map(|(trace_info, _)| json.hash_id(&trace_info.trace_id)),
+            Some(Err(problem)) => return Some(problem),
+            None => return Some(SelfCheckProblem::SpanNotRecorded),
+        };
+        (found.as_ref() != Some(&expected))
+            .then_some(SelfCheckProblem::TraceIdMismatch { expected, found })
+    });
+
+    SelfCheckReport {
+        problems: problem.into_iter().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::{
+        sdk::{
+            export::trace::SpanData,
+            trace::{Span, SpanProcessor},
+        },
+        trace::TraceResult,
+        Context,
+    };
+
+    use super::*;
+    use crate::json::IdSource;
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut output = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Capture {
+        type Writer = Capture;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    // Counts the spans ended on its provider
+    #[derive(Debug, Clone, Default)]
+    struct EndedSpans(Arc<Mutex<usize>>);
+
+    impl SpanProcessor for EndedSpans {
+        fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+        fn on_end(&self, _span: SpanData) {
+            *self.0.lock().unwrap() += 1;
+        }
+
+        fn force_flush(&self) -> TraceResult<()> {
+            Ok(())
+        }
+
+        fn shutdown(&mut self) -> TraceResult<()> {
+            Ok(())
+        }
+    }
+
+    fn dispatch(json: Json) -> (Dispatch, EndedSpans, TracerProvider) {
+        let ended = EndedSpans::default();
+        let provider = TracerProvider::builder()
+            .with_span_processor(ended.clone())
+            .build();
+        let config =
+            SubscriberConfig::new(Some(provider.tracer("test")), json).with_writer(io::sink);
+        (Dispatch::new(build_subscriber(config)), ended, provider)
+    }
+
+    #[test]
+    fn self_check_passes_for_a_consistent_setup() {
+        let (dispatch, ended, _provider) = dispatch(Json::default());
+        assert_eq!(
+            self_check(&dispatch, &Json::default()),
+            SelfCheckReport::default()
+        );
+
+        // the check's span was never ended on the provider, so nothing could be exported
+        tracing::dispatcher::with_default(&dispatch, || {
+            tracing::info_span!("other").in_scope(|| {})
+        });
+        assert_eq!(*ended.0.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn self_check_applies_the_hasher() {
+        let json = Json::default().trace_id_hasher(Some(Arc::new(|id| id.to_uppercase())));
+        let (dispatch, _, _provider) = dispatch(json.clone());
+        assert!(self_check(&dispatch, &json).is_ok());
+    }
+
+    #[test]
+    fn self_check_reports_ids_resolved_from_elsewhere() {
+        // the OpenTelemetry layer doesn't make the span's context the current one
+        let json = Json::default().id_source(IdSource::CurrentContext);
+        let (dispatch, _, _provider) = dispatch(json.clone());
+        let report = self_check(&dispatch, &json);
+        assert!(matches!(
+            &report.problems[..],
+            [SelfCheckProblem::TraceIdMismatch { found: None, .. }]
+        ));
+    }
+
+    #[test]
+    fn self_check_reports_a_missing_otel_layer() {
+        let dispatch = Dispatch::new(build_subscriber(
+            SubscriberConfig::new(None::<Tracer>, Json::default()).with_writer(io::sink),
+        ));
+        let report = self_check(&dispatch, &Json::default());
+        assert_eq!(report.problems, [SelfCheckProblem::MissingOtelLayer]);
+    }
+
+    #[test]
+    fn self_check_reports_a_filtered_span() {
+        let (tracer, _provider) = {
+            let provider = TracerProvider::default();
+            (provider.tracer("test"), provider)
+        };
+        let subscriber = build_subscriber(
+            SubscriberConfig::new(Some(tracer), Json::default()).with_writer(io::sink),
+        )
+        .with(LevelFilter::OFF);
+        let report = self_check(&Dispatch::new(subscriber), &Json::default());
+        assert_eq!(report.problems, [SelfCheckProblem::SpanNotRecorded]);
+    }
+
+    // Takes longer to flush than the shutdown waits for
+    #[derive(Debug)]
+    struct SlowFlush;
+
+    impl SpanProcessor for SlowFlush {
+        fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+        fn on_end(&self, _span: SpanData) {}
+
+        fn force_flush(&self) -> TraceResult<()> {
+            thread::sleep(Duration::from_millis(200));
+            Ok(())
+        }
+
+        fn shutdown(&mut self) -> TraceResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn timed_out_shutdowns_leave_the_provider_installed() {
+        set_tracer_provider(
+            TracerProvider::builder()
+                .with_span_processor(SlowFlush)
+                .build(),
+        );
+
+        let result = shutdown_tracing(Duration::from_millis(10));
+        assert!(matches!(result, Err(ShutdownError::Timeout(_))));
+        assert!(!is_shut_down());
+
+        // not shut down for real, the other tests would format events as after a shutdown then
+        let installed = INSTALLED_PROVIDER.lock().unwrap().take();
+        assert!(installed.is_some());
+    }
+
+    fn lines(output: &Capture) -> Vec<serde_json::Value> {
+        let output = output.0.lock().unwrap();
+        output
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn shutdown_flushes_the_suppressed_lines_of_live_subscribers() {
+        let output = Capture::default();
+        let json = Json::default().dedup_window(Some(Duration::from_secs(60)));
+        let subscriber = build_subscriber(
+            SubscriberConfig::new(None::<Tracer>, json).with_writer(output.clone()),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                tracing::info!("repeated");
+            }
+            flush_dedup();
+        });
+
+        let lines = lines(&output);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["repeated"], 2);
+    }
+
+    #[test]
+    fn dropped_subscribers_are_no_longer_flushed() {
+        let output = Capture::default();
+        let json = Json::default().dedup_window(Some(Duration::from_secs(60)));
+        let subscriber = build_subscriber(
+            SubscriberConfig::new(None::<Tracer>, json).with_writer(output.clone()),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("repeated");
+            tracing::info!("repeated");
+        });
+
+        flush_dedup();
+        // neither the subscriber nor its flush entry hold the writer anymore
+        assert_eq!(Arc::strong_count(&output.0), 1);
+    }
+}
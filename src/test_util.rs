@@ -0,0 +1,198 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use futures_util::future::BoxFuture;
+use opentelemetry::{
+    sdk::{
+        export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::{Span, SpanProcessor, Tracer, TracerProvider},
+    },
+    trace::{TraceResult, TracerProvider as _},
+    Context,
+};
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
+
+use crate::trace::TraceInfo;
+
+// Records the exported spans in memory, to assert on them without a running collector
+#[derive(Debug, Clone, Default)]
+pub struct InMemorySpanExporter {
+    spans: Arc<Mutex<Vec<SpanData>>>,
+}
+
+impl InMemorySpanExporter {
+    // Drain the spans exported so far
+    pub fn take(&self) -> Vec<SpanData> {
+        std::mem::take(&mut *self.spans.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+impl SpanExporter for InMemorySpanExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        self.spans
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend(batch);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// Exports each sampled span to the `InMemorySpanExporter` as it ends, on the thread ending it.
+// The `SimpleSpanProcessor` exports on a background thread without waiting for it on flushes,
+// so recently ended spans could be missing from `FinishedSpans::take`.
+#[derive(Debug)]
+struct InMemorySpanProcessor(InMemorySpanExporter);
+
+impl SpanProcessor for InMemorySpanProcessor {
+    fn on_start(&self, _span: &mut Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        if span.span_context.is_sampled() {
+            self.0
+                .spans
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(span);
+        }
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        Ok(())
+    }
+}
+
+// The spans recorded by the provider of `in_memory_tracer`
+#[derive(Debug)]
+pub struct FinishedSpans {
+    provider: TracerProvider,
+    exporter: InMemorySpanExporter,
+}
+
+impl FinishedSpans {
+    // Flush the provider and drain the spans ended so far. Spans are only exported once closed,
+    // i.e. once all handles to them are dropped.
+    pub fn take(&self) -> Vec<SpanData> {
+        for result in self.provider.force_flush() {
+            result.expect("flushing the in-memory span exporter failed");
+        }
+        self.exporter.take()
+    }
+}
+
+// A tracer exporting to an `InMemorySpanExporter` rather than a collector, for the
+// `tracing_opentelemetry` layer of a test subscriber. Not installed as the global provider.
+pub fn in_memory_tracer() -> (Tracer, FinishedSpans) {
+    let exporter = InMemorySpanExporter::default();
+    let provider = TracerProvider::builder()
+        .with_span_processor(InMemorySpanProcessor(exporter.clone()))
+        .build();
+    let tracer = provider.tracer("test-util");
+    (tracer, FinishedSpans { provider, exporter })
+}
+
+// Assert that both `TraceInfo`s belong to the same trace. The trace ids are compared
+// canonicalized (trimmed, lowercase, left-padded to 128-bit), so ids from different
+// propagation formats compare equal when they refer to the same trace.
+#[track_caller]
+pub fn assert_same_trace(a: &TraceInfo, b: &TraceInfo) {
+    let (left, right) = (
+        canonical_trace_id(&a.trace_id),
+        canonical_trace_id(&b.trace_id),
+    );
+    assert!(
+        left == right,
+        "spans belong to different traces\n  left trace_id: {} (span_id: {})\n right trace_id: {} (span_id: {})",
+        a.trace_id,
+        a.span_id,
+        b.trace_id,
+        b.span_id,
+    );
+}
+
+fn canonical_trace_id(trace_id: &str) -> String {
+    format!("{:0>32}", trace_id.trim().to_ascii_lowercase())
+}
+
+// Assert that `line` (optionally terminated by a newline) is a single valid JSON value with no
+// duplicate keys in any object, which `serde_json` silently accepts by keeping the last one.
+#[track_caller]
+pub fn assert_valid_json_line(line: &str) {
+    let json = line.strip_suffix('\n').unwrap_or(line);
+    assert!(!json.contains('\n'), "expected a single line, got:\n{line}");
+
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    if let Err(err) =
+        NoDuplicateKeys::deserialize(&mut deserializer).and_then(|_| deserializer.end())
+    {
+        panic!("invalid JSON line: {err}\n  line: {json}");
+    }
+}
+
+// Deserializes any JSON value, failing on the first object with a key seen twice
+struct NoDuplicateKeys;
+
+impl<'de> Deserialize<'de> for NoDuplicateKeys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(NoDuplicateKeysVisitor)
+    }
+}
+
+struct NoDuplicateKeysVisitor;
+
+impl<'de> Visitor<'de> for NoDuplicateKeysVisitor {
+    type Value = NoDuplicateKeys;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E> {
+        Ok(NoDuplicateKeys)
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<Self::Value, E> {
+        Ok(NoDuplicateKeys)
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<Self::Value, E> {
+        Ok(NoDuplicateKeys)
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E> {
+        Ok(NoDuplicateKeys)
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<Self::Value, E> {
+        Ok(NoDuplicateKeys)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(NoDuplicateKeys)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        while seq.next_element::<NoDuplicateKeys>()?.is_some() {}
+        Ok(NoDuplicateKeys)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut keys = HashSet::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if !keys.insert(key.clone()) {
+                return Err(de::Error::custom(format!("duplicate key {key:?}")));
+            }
+            map.next_value::<NoDuplicateKeys>()?;
+        }
+        Ok(NoDuplicateKeys)
+    }
+}
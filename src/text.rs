@@ -0,0 +1,158 @@
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+use crate::json::Json;
+
+// Renders a concise human-readable line like
+// `{timestamp} INFO [trace_id=.. span_id=..] target: message key=value` for local development.
+// It's the line `HumanAndJson` writes as well, with the timestamp, target and ids as configured
+// on the wrapped `Json`. The ids are omitted outside of a trace.
+#[derive(Clone, Default)]
+pub struct TextFormat {
+    json: Json,
+}
+
+impl TextFormat {
+    pub fn new(json: Json) -> Self {
+        Self { json }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for TextFormat
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        if self.json.is_suppressed(event.metadata().target()) {
+            return Ok(());
+        }
+
+        let line = self.json.human_line(ctx, event, false)?;
+        writeln!(writer, "{line}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use chrono::{TimeZone, Utc};
+    use opentelemetry::{
+        sdk::trace::{Tracer, TracerProvider},
+        trace::{TraceFlags, TracerProvider as _},
+    };
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::{
+        fmt::{format::DefaultFields, MakeWriter},
+        layer::{Layered, SubscriberExt},
+        Registry,
+    };
+
+    use super::*;
+    use crate::{
+        json::{HumanAndJson, LineOrder, TimestampSource},
+        trace::{remote_trace_span, RemoteTraceContext, TraceInfo},
+    };
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MakeWriter<'_> for Output {
+        type Writer = Self;
+
+        fn make_writer(&self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    fn json() -> Json {
+        let clock = Arc::new(|| Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap());
+        Json::default().timestamp_source(TimestampSource::Clock(clock))
+    }
+
+    type WithOtel = Layered<OpenTelemetryLayer<Registry, Tracer>, Registry>;
+
+    // The output of `format` for an event within a span continuing a remote trace
+    fn format<F>(format: F) -> String
+    where
+        F: FormatEvent<WithOtel, DefaultFields> + Send + Sync + 'static,
+    {
+        let output = Output::default();
+        let provider = TracerProvider::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .event_format(format)
+                    .with_writer(output.clone()),
+            );
+        tracing::subscriber::with_default(subscriber, || {
+            let remote = RemoteTraceContext {
+                info: TraceInfo {
+                    trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".into(),
+                    span_id: "00f067aa0ba902b7".into(),
+                },
+                trace_flags: TraceFlags::SAMPLED,
+                ..Default::default()
+            };
+            let span = remote_trace_span(tracing::info_span!("request"), &remote).unwrap();
+            span.in_scope(|| tracing::info!(answer = 42, "hello"));
+        });
+
+        let output = output.0.lock().unwrap();
+        String::from_utf8(output.clone()).unwrap()
+    }
+
+    #[test]
+    fn lines_carry_the_remote_trace_id() {
+        let line = format(TextFormat::new(json()));
+        assert!(line.starts_with("2024-01-02T03:04:05"), "{line}");
+        assert!(line.contains(" INFO [trace_id=4bf92f3577b34da6a3ce929d0e0e4736 span_id="));
+        assert!(line.ends_with("] tracing_stable_trace_id_example::text::tests: hello answer=42\n"));
+    }
+
+    #[test]
+    fn lines_match_the_human_lines_of_human_and_json() {
+        let text = format(TextFormat::new(json()));
+        let both = format(HumanAndJson::new(json(), LineOrder::HumanFirst));
+        // the span ids differ between the two runs
+        let without_span_id = |line: &str| {
+            let (head, tail) = line.split_once(" span_id=").unwrap();
+            format!("{head}{}", &tail[16..])
+        };
+        assert_eq!(
+            without_span_id(&text),
+            without_span_id(both.lines().next().unwrap()) + "\n"
+        );
+    }
+
+    #[test]
+    fn the_hasher_applies() {
+        let json = json().trace_id_hasher(Some(Arc::new(|_| "hashed".into())));
+        let line = format(TextFormat::new(json));
+        assert!(line.contains("[trace_id=hashed span_id=hashed] "), "{line}");
+    }
+}
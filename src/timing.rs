@@ -0,0 +1,108 @@
+use std::time::{Duration, Instant};
+
+use tracing::{span, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+struct SpanStart(Instant);
+
+// Opt-in layer emitting a "span closed" event with the total `duration_ms` whenever a span
+// closes. The event is a child of the closed span, so the `Json` output carries its name and
+// trace/span ids. It has to be added *before* the OpenTelemetry layer, which drops its data
+// for the span on close.
+pub struct SpanCloseLayer;
+
+impl<S> Layer<S> for SpanCloseLayer
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanStart(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(start) = span.extensions().get::<SpanStart>().map(|start| start.0) else {
+            return;
+        };
+        drop(span);
+
+        let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+        tracing::info!(parent: &id, duration_ms, "span closed");
+    }
+}
+
+// Busy (entered) and idle (exited, e.g. awaiting) time of a span so far, kept in its
+// extensions by `SpanTimingLayer`
+pub(crate) struct SpanTiming {
+    busy: Duration,
+    idle: Duration,
+    last: Instant,
+    entered: usize,
+}
+
+impl SpanTiming {
+    // Includes the time since the span was last entered or exited
+    pub(crate) fn busy_idle(&self) -> (Duration, Duration) {
+        let since_last = self.last.elapsed();
+        match self.entered {
+            0 => (self.busy, self.idle + since_last),
+            _ => (self.busy + since_last, self.idle),
+        }
+    }
+}
+
+// Opt-in layer tracking how long each span was entered (busy) and exited (idle), like the
+// timings of tracing-subscriber's `FmtSpan::CLOSE`. With it added, the `Json` output carries
+// `busy_ms` and `idle_ms` in the `span` entry, pointing to spans mostly awaiting.
+pub struct SpanTimingLayer;
+
+impl<S> Layer<S> for SpanTimingLayer
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanTiming {
+                busy: Duration::ZERO,
+                idle: Duration::ZERO,
+                last: Instant::now(),
+                entered: 0,
+            });
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(timing) = extensions.get_mut::<SpanTiming>() {
+            // a span can be entered multiple times at once, e.g. from several threads
+            if timing.entered == 0 {
+                let now = Instant::now();
+                timing.idle += now - timing.last;
+                timing.last = now;
+            }
+            timing.entered += 1;
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(timing) = extensions.get_mut::<SpanTiming>() {
+            timing.entered = timing.entered.saturating_sub(1);
+            if timing.entered == 0 {
+                let now = Instant::now();
+                timing.busy += now - timing.last;
+                timing.last = now;
+            }
+        }
+    }
+}
@@ -1,28 +1,672 @@
-use opentelemetry::trace::{SpanId, SpanRef, TraceContextExt, TraceFlags, TraceId};
-use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow, collections::HashMap, error::Error, fmt, num::ParseIntError, str::FromStr,
+    sync::Once,
+};
+
+use opentelemetry::{
+    global,
+    sdk::trace::{IdGenerator, RandomIdGenerator},
+    trace::{
+        SamplingDecision, SpanBuilder, SpanContext, SpanId, SpanRef, TraceContextExt, TraceError,
+        TraceFlags, TraceId, TraceState,
+    },
+};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use tracing::Span;
-use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_opentelemetry::{OpenTelemetrySpanExt, OtelData};
+use tracing_subscriber::{registry::LookupSpan, Registry};
 
-#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq, Eq)]
+#[derive(Clone, Deserialize, Serialize, Default, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct RemoteTraceContext {
     #[serde(flatten)]
     pub info: TraceInfo,
-    pub trace_flags: u8,
+    /// Serialized as the numeric flags. Some upstreams only send a `sampled` boolean instead,
+    /// which is accepted as well.
+    #[serde(
+        alias = "sampled",
+        deserialize_with = "deserialize_trace_flags",
+        serialize_with = "serialize_trace_flags"
+    )]
+    pub trace_flags: TraceFlags,
+    /// The W3C `tracestate` header value, vendor specific data like upstream sampling hints.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_state: Option<String>,
+    /// Free-form data piggybacking on the propagation, preserved as is but not used for the span.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<serde_json::Value>,
+}
+
+fn serialize_trace_flags<S: Serializer>(
+    trace_flags: &TraceFlags,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u8(trace_flags.to_u8())
 }
 
-// Set parent context and return reference
-pub fn remote_trace_span(span: Span, trace_context: &RemoteTraceContext) -> Span {
-    span.set_parent(opentelemetry::Context::new().with_remote_span_context(
-        opentelemetry::trace::SpanContext::new(
-            TraceId::from_hex(&trace_context.info.trace_id).unwrap(),
-            SpanId::from_hex(&trace_context.info.span_id).unwrap(),
-            TraceFlags::new(trace_context.trace_flags),
+fn deserialize_trace_flags<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<TraceFlags, D::Error> {
+    struct TraceFlagsVisitor;
+
+    impl<'de> Visitor<'de> for TraceFlagsVisitor {
+        type Value = TraceFlags;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("numeric trace flags or a sampled boolean")
+        }
+
+        fn visit_bool<E: de::Error>(self, sampled: bool) -> Result<TraceFlags, E> {
+            Ok(TraceFlags::default().with_sampled(sampled))
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<TraceFlags, E> {
+            u8::try_from(value)
+                .map(TraceFlags::new)
+                .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(value), &self))
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<TraceFlags, E> {
+            u8::try_from(value)
+                .map(TraceFlags::new)
+                .map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self))
+        }
+    }
+
+    deserializer.deserialize_any(TraceFlagsVisitor)
+}
+
+impl RemoteTraceContext {
+    /// Build an OTel `SpanBuilder` continuing the remote trace, for callers which want to set the
+    /// span kind, attributes or start time themselves. The returned context holds the remote
+    /// parent and has to be used to start the span, e.g. `builder.start_with_context(&tracer, &cx)`.
+    pub fn into_span_builder(
+        self,
+        name: impl Into<Cow<'static, str>>,
+    ) -> Result<(SpanBuilder, opentelemetry::Context), TraceParseError> {
+        let span_context = self.span_context()?;
+        let builder = SpanBuilder::from_name(name).with_trace_id(span_context.trace_id());
+        let parent_cx = opentelemetry::Context::new().with_remote_span_context(span_context);
+        Ok((builder, parent_cx))
+    }
+
+    // Parse an AWS X-Ray trace header like
+    // `Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1`.
+    // The root id's timestamp and random segments together form the 32 hex chars of the trace id.
+    // Without a `Parent` there's no span to continue from, so `None` is returned.
+    pub fn from_xray(header: &str) -> Option<Self> {
+        let (mut root, mut parent, mut sampled) = (None, None, false);
+        for part in header.split(';') {
+            // e.g. the empty segment after a trailing `;`
+            let Some(part) = part.trim().split_once('=') else {
+                continue;
+            };
+            match part {
+                ("Root", value) => root = Some(value.to_ascii_lowercase()),
+                ("Parent", value) => parent = Some(value.to_ascii_lowercase()),
+                ("Sampled", value) => sampled = value == "1",
+                _ => {}
+            }
+        }
+
+        let root = root?;
+        let mut segments = root.split('-');
+        let (version, timestamp, random) = (segments.next()?, segments.next()?, segments.next()?);
+        if version != "1"
+            || segments.next().is_some()
+            || !is_lower_hex(timestamp, 8)
+            || !is_lower_hex(random, 24)
+        {
+            return None;
+        }
+
+        let parent = parent.filter(|parent| is_lower_hex(parent, 16))?;
+
+        Some(Self {
+            info: TraceInfo {
+                trace_id: format!("{timestamp}{random}"),
+                span_id: parent,
+            },
+            trace_flags: TraceFlags::default().with_sampled(sampled),
+            trace_state: None,
+            meta: None,
+        })
+    }
+
+    // Build the context from the B3 (Zipkin) headers `X-B3-TraceId`, `X-B3-SpanId` and the
+    // optional `X-B3-Sampled`. B3 trace ids may be 64 bit, those are left-padded with zeros to
+    // the 128 bit W3C form. `d` (debug) counts as sampled, a missing decision as not sampled.
+    pub fn from_b3(trace_id: &str, span_id: &str, sampled: Option<&str>) -> Option<Self> {
+        let trace_id = trace_id.trim().to_ascii_lowercase();
+        let span_id = span_id.trim().to_ascii_lowercase();
+
+        let trace_id = match trace_id.len() {
+            16 => format!("{trace_id:0>32}"),
+            _ => trace_id,
+        };
+        let is_zero = |value: &str| value.bytes().all(|b| b == b'0');
+        if !is_lower_hex(&trace_id, 32) || is_zero(&trace_id) {
+            return None;
+        }
+        if !is_lower_hex(&span_id, 16) || is_zero(&span_id) {
+            return None;
+        }
+
+        let trace_flags = match sampled.map(str::trim) {
+            Some("1" | "true" | "d") => TraceFlags::SAMPLED,
+            Some("0" | "false") | None => TraceFlags::default(),
+            Some(_) => return None,
+        };
+
+        Some(Self {
+            info: TraceInfo { trace_id, span_id },
+            trace_flags,
+            trace_state: None,
+            meta: None,
+        })
+    }
+
+    // Parse the single B3 header `b3: {trace_id}-{span_id}[-{sampling}[-{parent_span_id}]]`.
+    // A bare sampling decision like `b3: 0` carries no ids to continue from, so it's `None`.
+    pub fn from_b3_single(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let (trace_id, span_id) = (parts.next()?, parts.next()?);
+        let sampled = parts.next();
+        // an optional 4th part is the parent span id, which isn't needed to continue the trace
+        if parts.nth(1).is_some() {
+            return None;
+        }
+        Self::from_b3(trace_id, span_id, sampled)
+    }
+
+    // Parse Jaeger's `uber-trace-id: {trace_id}:{span_id}:{parent_span_id}:{flags}`. Jaeger
+    // drops leading zeros, so shorter ids are left-padded to the W3C lengths. The parent span id
+    // isn't needed to continue the trace, the lowest flag bit is the sampling decision.
+    pub fn from_uber(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split(':');
+        let (trace_id, span_id, _parent_span_id, flags) =
+            (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() || trace_id.len() > 32 || span_id.len() > 16 {
+            return None;
+        }
+
+        let trace_id = format!("{:0>32}", trace_id.to_ascii_lowercase());
+        let span_id = format!("{:0>16}", span_id.to_ascii_lowercase());
+        let is_zero = |value: &str| value.bytes().all(|b| b == b'0');
+        if !is_lower_hex(&trace_id, 32) || is_zero(&trace_id) {
+            return None;
+        }
+        if !is_lower_hex(&span_id, 16) || is_zero(&span_id) {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+
+        Some(Self {
+            info: TraceInfo { trace_id, span_id },
+            trace_flags: TraceFlags::new(flags & TraceFlags::SAMPLED.to_u8()),
+            trace_state: None,
+            meta: None,
+        })
+    }
+
+    // Parse Google Cloud's `X-Cloud-Trace-Context: {trace_id}/{span_id}[;o={options}]`, where the
+    // span id is a decimal u64 and `o=1` marks the trace as sampled.
+    pub fn from_cloud_trace_context(header: &str) -> Option<Self> {
+        let (ids, options) = match header.trim().split_once(';') {
+            Some((ids, options)) => (ids, Some(options)),
+            None => (header.trim(), None),
+        };
+        let (trace_id, span_id) = ids.split_once('/')?;
+
+        let trace_id = trace_id.to_ascii_lowercase();
+        if !is_lower_hex(&trace_id, 32) || trace_id.bytes().all(|b| b == b'0') {
+            return None;
+        }
+        let span_id = match span_id.parse::<u64>().ok()? {
+            0 => return None,
+            span_id => format!("{span_id:016x}"),
+        };
+        let trace_flags = match options {
+            Some("o=1") => TraceFlags::SAMPLED,
+            Some("o=0") | None => TraceFlags::default(),
+            Some(_) => return None,
+        };
+
+        Some(Self {
+            info: TraceInfo { trace_id, span_id },
+            trace_flags,
+            trace_state: None,
+            meta: None,
+        })
+    }
+
+    // Parse a W3C `traceparent` like `00-9d96f6d506048d33796d850a09797e55-0db1818f6e5514ee-01`
+    // https://www.w3.org/TR/trace-context/#traceparent-header-field-values
+    pub fn from_traceparent(header: &str) -> Result<Self, TraceParseError> {
+        let mut parts = header.trim().split('-');
+        let (Some(version), Some(trace_id), Some(span_id), Some(trace_flags)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(TraceParseError::MalformedHeader);
+        };
+
+        if !is_lower_hex(version, 2) || version == "ff" {
+            return Err(TraceParseError::UnsupportedVersion(version.to_owned()));
+        }
+        // version 00 consists of exactly four parts, future versions may append more
+        if version == "00" && parts.next().is_some() {
+            return Err(TraceParseError::MalformedHeader);
+        }
+
+        let invalid = |field, value: &str| TraceParseError::InvalidField {
+            field,
+            value: value.to_owned(),
+        };
+        // all zero ids are explicitly invalid
+        let is_zero = |value: &str| value.bytes().all(|b| b == b'0');
+        if !is_lower_hex(trace_id, 32) || is_zero(trace_id) {
+            return Err(invalid("trace_id", trace_id));
+        }
+        if !is_lower_hex(span_id, 16) || is_zero(span_id) {
+            return Err(invalid("span_id", span_id));
+        }
+        if !is_lower_hex(trace_flags, 2) {
+            return Err(invalid("trace_flags", trace_flags));
+        }
+
+        Ok(Self {
+            info: TraceInfo {
+                trace_id: trace_id.to_owned(),
+                span_id: span_id.to_owned(),
+            },
+            trace_flags: u8::from_str_radix(trace_flags, 16)
+                .map(TraceFlags::new)
+                .map_err(|_| invalid("trace_flags", trace_flags))?,
+            trace_state: None,
+            meta: None,
+        })
+    }
+
+    // Read the context a parent process handed down through the `TRACEPARENT` (and optional
+    // `TRACESTATE`) env variables, so short-lived subprocesses like CLI tools continue its
+    // trace. `None` when unset or malformed.
+    pub fn from_env() -> Option<Self> {
+        let mut trace_context = parse_traceparent(&std::env::var("TRACEPARENT").ok()?)?;
+        trace_context.trace_state = std::env::var("TRACESTATE").ok();
+        Some(trace_context)
+    }
+
+    // Extract the context from the `traceparent` and optional `tracestate` headers of an
+    // inbound request, `None` if `traceparent` is missing or malformed
+    #[cfg(feature = "http")]
+    pub fn from_headers(headers: &http::HeaderMap) -> Option<Self> {
+        let traceparent = headers.get("traceparent")?.to_str().ok()?;
+        let mut trace_context = parse_traceparent(traceparent)?;
+        trace_context.trace_state = headers
+            .get("tracestate")
+            .and_then(|trace_state| trace_state.to_str().ok())
+            .map(str::trim)
+            .filter(|trace_state| !trace_state.is_empty())
+            .map(str::to_owned);
+        Some(trace_context)
+    }
+
+    // Like `from_headers` for the ASCII metadata of an inbound gRPC request, binary (`-bin`)
+    // entries are never looked at
+    #[cfg(feature = "tonic")]
+    pub fn from_metadata(metadata: &tonic::metadata::MetadataMap) -> Option<Self> {
+        let traceparent = metadata.get("traceparent")?.to_str().ok()?;
+        let mut trace_context = parse_traceparent(traceparent)?;
+        trace_context.trace_state = metadata
+            .get("tracestate")
+            .and_then(|trace_state| trace_state.to_str().ok())
+            .map(str::trim)
+            .filter(|trace_state| !trace_state.is_empty())
+            .map(str::to_owned);
+        Some(trace_context)
+    }
+
+    // Extract the context from the `traceparent` and optional `tracestate` String attributes of
+    // an SQS/SNS message, `None` if `traceparent` is missing, of another type or malformed
+    #[cfg(feature = "aws")]
+    pub fn from_message_attributes(
+        attributes: &std::collections::HashMap<String, MessageAttributeValue>,
+    ) -> Option<Self> {
+        let traceparent = attributes.get("traceparent")?.as_str()?;
+        let mut trace_context = parse_traceparent(traceparent)?;
+        trace_context.trace_state = attributes
+            .get("tracestate")
+            .and_then(MessageAttributeValue::as_str)
+            .filter(|trace_state| !trace_state.is_empty())
+            .map(str::to_owned);
+        Some(trace_context)
+    }
+
+    // Write the context as `traceparent` (and `tracestate` if set) String attributes into the
+    // attributes of an outgoing SQS/SNS message
+    #[cfg(feature = "aws")]
+    pub fn inject_message_attributes(
+        &self,
+        attributes: &mut std::collections::HashMap<String, MessageAttributeValue>,
+    ) {
+        let traceparent =
+            format_traceparent(&self.info.trace_id, &self.info.span_id, self.trace_flags);
+        attributes.insert(
+            "traceparent".into(),
+            MessageAttributeValue::string(traceparent),
+        );
+        if let Some(trace_state) = &self.trace_state {
+            attributes.insert(
+                "tracestate".into(),
+                MessageAttributeValue::string(trace_state.clone()),
+            );
+        }
+    }
+
+    // Deserialize the context from the sub-object at the JSON `pointer` (e.g. `/meta/trace`) of
+    // an arbitrary message, `None` if the pointer misses or the object has the wrong shape
+    pub fn from_json_value(value: &serde_json::Value, pointer: &str) -> Option<Self> {
+        Self::deserialize(value.pointer(pointer)?).ok()
+    }
+
+    // Deserialize the context from the `tracing` entry of a GraphQL request's `extensions`,
+    // `None` if the entry is absent or has the wrong shape
+    pub fn from_graphql_extensions(
+        extensions: &serde_json::Map<String, serde_json::Value>,
+    ) -> Option<Self> {
+        Self::deserialize(extensions.get("tracing")?).ok()
+    }
+
+    // Write the context as the `tracing` entry into the `extensions` of an outgoing GraphQL
+    // request, replacing an existing one
+    pub fn inject_graphql_extensions(
+        &self,
+        extensions: &mut serde_json::Map<String, serde_json::Value>,
+    ) {
+        if let Ok(value) = serde_json::to_value(self) {
+            extensions.insert("tracing".to_owned(), value);
+        }
+    }
+
+    /// `Debug` output with the full trace id, the regular one shortens it.
+    pub fn full_debug(&self) -> impl fmt::Debug + '_ {
+        FullDebug(self)
+    }
+
+    /// Check that the trace id is 32 and the span id 16 hex chars and neither is all zeros.
+    /// `TraceId::from_hex` also accepts shorter ids, silently correlating with the wrong trace.
+    pub fn validate(&self) -> Result<(), TraceParseError> {
+        let is_hex =
+            |value: &str, len| value.len() == len && value.bytes().all(|b| b.is_ascii_hexdigit());
+        let is_zero = |value: &str| value.bytes().all(|b| b == b'0');
+
+        for (field, value, len) in [
+            ("trace_id", &self.info.trace_id, 32),
+            ("span_id", &self.info.span_id, 16),
+        ] {
+            if !is_hex(value, len) || is_zero(value) {
+                return Err(TraceParseError::InvalidField {
+                    field,
+                    value: value.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // The ids usually stem from untrusted inbound requests, so they must not be unwrapped
+    fn span_context(&self) -> Result<SpanContext, TraceParseError> {
+        self.validate()?;
+        let trace_id = TraceId::from_hex(&self.info.trace_id).map_err(|source| {
+            TraceParseError::InvalidHex {
+                field: "trace_id",
+                source,
+            }
+        })?;
+        let span_id =
+            SpanId::from_hex(&self.info.span_id).map_err(|source| TraceParseError::InvalidHex {
+                field: "span_id",
+                source,
+            })?;
+
+        let trace_state = match &self.trace_state {
+            Some(trace_state) => {
+                TraceState::from_str(trace_state).map_err(|_| TraceParseError::InvalidField {
+                    field: "trace_state",
+                    value: trace_state.clone(),
+                })?
+            }
+            None => TraceState::default(),
+        };
+
+        Ok(SpanContext::new(
+            trace_id,
+            span_id,
+            self.trace_flags,
             true,
-            Default::default(),
-        ),
-    ));
-    span
+            trace_state,
+        ))
+    }
+}
+
+// Debug output ends up in error messages and logs, so only show enough of the trace id to
+// tell traces apart, like `9d96f6d5…7e55`
+impl fmt::Debug for RemoteTraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let trace_id = &self.info.trace_id;
+        let redacted = match (
+            trace_id.get(..8),
+            trace_id.get(trace_id.len().saturating_sub(4)..),
+        ) {
+            (Some(head), Some(tail)) if trace_id.len() > 12 => format!("{head}…{tail}"),
+            _ => trace_id.clone(),
+        };
+
+        f.debug_struct("RemoteTraceContext")
+            .field("trace_id", &redacted)
+            .field("span_id", &self.info.span_id)
+            .field("trace_flags", &self.trace_flags)
+            .field("trace_state", &self.trace_state)
+            .field("meta", &self.meta)
+            .finish()
+    }
+}
+
+struct FullDebug<'a>(&'a RemoteTraceContext);
+
+impl fmt::Debug for FullDebug<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteTraceContext")
+            .field("info", &self.0.info)
+            .field("trace_flags", &self.0.trace_flags)
+            .field("trace_state", &self.0.trace_state)
+            .field("meta", &self.0.meta)
+            .finish()
+    }
+}
+
+// Set parent context and return reference, fails if the context holds malformed ids
+pub fn remote_trace_span(
+    span: Span,
+    trace_context: &RemoteTraceContext,
+) -> Result<Span, TraceParseError> {
+    remote_trace_span_with_options(span, trace_context, RemoteSpanOptions::default())
+}
+
+// Like `remote_trace_span`, parsing the raw `traceparent` header value first
+pub fn remote_trace_span_from_str(span: Span, traceparent: &str) -> Result<Span, TraceParseError> {
+    remote_trace_span(span, &RemoteTraceContext::from_traceparent(traceparent)?)
+}
+
+// Like `remote_trace_span`, but continue under `new_trace_id` instead of the remote trace id,
+// for stitching traces across systems with incompatible ids. The remote span id stays the
+// parent, and the span links to the original remote span. Fails if either context is malformed.
+pub fn remote_trace_span_remapped(
+    span: Span,
+    trace_context: &RemoteTraceContext,
+    new_trace_id: &str,
+) -> Result<Span, TraceParseError> {
+    let original = trace_context.span_context()?;
+    let remapped = RemoteTraceContext {
+        info: TraceInfo {
+            trace_id: new_trace_id.to_owned(),
+            span_id: trace_context.info.span_id.clone(),
+        },
+        ..trace_context.clone()
+    };
+
+    let span = remote_trace_span(span, &remapped)?;
+    span.add_link(original);
+    Ok(span)
+}
+
+// Extract the context of an inbound request from `carrier` (e.g. its headers) with the global
+// text map propagator, see `setup::init_propagator`. The result holds the remote span context
+// if the carrier had a valid one, plus whatever else the propagators extract, like baggage.
+pub fn extract_remote_context(carrier: &HashMap<String, String>) -> opentelemetry::Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(carrier))
+}
+
+// Like `remote_trace_span`, but continue the remote span of an already extracted context, e.g.
+// by `extract_remote_context`. The rest of the context, like baggage, is kept.
+pub fn remote_trace_span_from_context(
+    span: Span,
+    cx: &opentelemetry::Context,
+) -> Result<Span, TraceParseError> {
+    let span_context = cx.span().span_context().clone();
+    if !span_context.is_valid() || !span_context.is_remote() {
+        return Err(TraceParseError::NoRemoteContext);
+    }
+
+    if !mark_remote_parent(&span) {
+        report_double_attach();
+    }
+    span.set_parent(cx.clone());
+    Ok(span)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RemoteSpanOptions {
+    /// Keep the trace id but replace the remote parent's span id with a freshly generated one,
+    /// so replayed recorded requests don't collide with the original spans in the backend.
+    pub replay_safe: bool,
+    /// Fail with `TraceParseError::AlreadyParented` instead of only reporting it when the span
+    /// already got a remote parent attached.
+    pub strict: bool,
+    /// Mark the span to be sampled by `sampling::ForceSampling`, e.g. for requests carrying a
+    /// debug header.
+    pub force_sample: bool,
+    /// Read the span's context back after attaching the parent and report through the global
+    /// OpenTelemetry error handler if its trace id differs, e.g. without an OpenTelemetry layer.
+    /// Meant for development, as it fixes the span's sampling decision right away.
+    pub verify_propagation: bool,
+}
+
+pub fn remote_trace_span_with_options(
+    span: Span,
+    trace_context: &RemoteTraceContext,
+    options: RemoteSpanOptions,
+) -> Result<Span, TraceParseError> {
+    let mut span_context = trace_context.span_context()?;
+    if options.replay_safe {
+        span_context = SpanContext::new(
+            span_context.trace_id(),
+            RandomIdGenerator::default().new_span_id(),
+            span_context.trace_flags(),
+            true,
+            span_context.trace_state().clone(),
+        );
+    }
+
+    if !mark_remote_parent(&span) {
+        if options.strict {
+            return Err(TraceParseError::AlreadyParented);
+        }
+        report_double_attach();
+    }
+    if options.force_sample {
+        crate::sampling::force_sample(&span);
+    }
+
+    let trace_id = span_context.trace_id();
+    span.set_parent(opentelemetry::Context::new().with_remote_span_context(span_context));
+
+    if options.verify_propagation && !span.is_disabled() {
+        let propagated = span.context().span().span_context().trace_id();
+        if propagated != trace_id {
+            global::handle_error(TraceError::from(format!(
+                "remote trace id {trace_id} didn't propagate to span `{}`, it has trace id {propagated}",
+                span.metadata().map_or("", |meta| meta.name()),
+            )));
+        }
+    }
+    Ok(span)
+}
+
+// Marks spans a remote parent was attached to, so a second `set_parent` doesn't silently
+// override the first one
+struct RemoteParentAttached;
+
+// Returns false if the span was already marked. Spans outside of a `Registry` can't carry the
+// marker and always pass.
+fn mark_remote_parent(span: &Span) -> bool {
+    span.with_subscriber(|(id, dispatch)| {
+        let span = dispatch.downcast_ref::<Registry>()?.span(id)?;
+        let mut extensions = span.extensions_mut();
+        if extensions.get_mut::<RemoteParentAttached>().is_some() {
+            return Some(false);
+        }
+        extensions.insert(RemoteParentAttached);
+        Some(true)
+    })
+    .flatten()
+    .unwrap_or(true)
+}
+
+fn report_double_attach() {
+    static REPORTED: Once = Once::new();
+    REPORTED.call_once(|| {
+        opentelemetry::global::handle_error(TraceError::from(
+            "remote parent attached twice to the same span, the first parent is overridden",
+        ));
+    });
+}
+
+/// An SQS/SNS message attribute in the shape of the AWS APIs (`{"DataType":..,"StringValue":..}`),
+/// which also accepts the camelCase keys of SQS Lambda events.
+#[cfg(feature = "aws")]
+#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct MessageAttributeValue {
+    #[serde(alias = "dataType")]
+    pub data_type: String,
+    #[serde(
+        alias = "stringValue",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub string_value: Option<String>,
+}
+
+#[cfg(feature = "aws")]
+impl MessageAttributeValue {
+    pub fn string(value: impl Into<String>) -> Self {
+        Self {
+            data_type: "String".into(),
+            string_value: Some(value.into()),
+        }
+    }
+
+    // The value of `String` attributes, `None` for other data types like `Binary`
+    fn as_str(&self) -> Option<&str> {
+        match self.data_type.as_str() {
+            "String" => self.string_value.as_deref(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq, Eq)]
@@ -32,7 +676,45 @@ pub struct TraceInfo {
     pub span_id: String,
 }
 
+impl TraceInfo {
+    // Format the ids as a W3C `traceparent`, the flags being the sampling decision to send along
+    pub fn to_traceparent(&self, flags: TraceFlags) -> String {
+        format_traceparent(&self.trace_id, &self.span_id, flags)
+    }
+
+    // Parse the ids out of a W3C `traceparent`, dropping its flags
+    pub fn from_traceparent(value: &str) -> Result<Self, TraceParseError> {
+        RemoteTraceContext::from_traceparent(value).map(|trace_context| trace_context.info)
+    }
+}
+
+/// Serialize a `TraceInfo` field as a single `traceparent` string instead of the
+/// `{"traceId":..,"spanId":..}` object, through `#[serde(with = "trace::traceparent")]`. A
+/// `TraceInfo` carries no sampling decision, so the flags are written as `00`.
+pub mod traceparent {
+    use opentelemetry::trace::TraceFlags;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    use super::TraceInfo;
+
+    pub fn serialize<S: Serializer>(info: &TraceInfo, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&info.to_traceparent(TraceFlags::default()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TraceInfo, D::Error> {
+        let value = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        TraceInfo::from_traceparent(&value).map_err(de::Error::custom)
+    }
+}
+
 pub(crate) fn trace_info_from_ref(span_ref: SpanRef<'_>) -> Option<TraceInfo> {
+    trace_info_and_flags_from_ref(span_ref).map(|(info, _)| info)
+}
+
+// Like `trace_info_from_ref`, additionally returning the sampling decision of the trace
+pub(crate) fn trace_info_and_flags_from_ref(
+    span_ref: SpanRef<'_>,
+) -> Option<(TraceInfo, TraceFlags)> {
     let span_context = span_ref.span_context();
     let trace_id = span_context.trace_id();
 
@@ -40,8 +722,1178 @@ pub(crate) fn trace_info_from_ref(span_ref: SpanRef<'_>) -> Option<TraceInfo> {
         return None;
     }
 
-    Some(TraceInfo {
+    let trace_info = TraceInfo {
         trace_id: trace_id.to_string(),
         span_id: span_context.span_id().to_string(),
+    };
+    Some((trace_info, span_context.trace_flags()))
+}
+
+pub(crate) fn trace_info_from_otel_data(otel_data: &OtelData) -> Option<TraceInfo> {
+    let from_parent = trace_info_from_ref(otel_data.parent_cx.span()).map(|mut info| {
+        // if the SpanBuilder contains a valid span_id we use its span_id instead
+        // of the extracted one, because it refers to the more accurate span.
+        match otel_data.builder.span_id {
+            Some(SpanId::INVALID) if info.span_id != SpanId::INVALID.to_string() => {
+                report_conflicting_span_ids(&info.span_id);
+            }
+            Some(span_id) => info.span_id = span_id.to_string(),
+            None => {}
+        }
+        info
+    });
+
+    // a locally rooted span has no valid parent context, but its builder already holds the
+    // ids generated for the span itself
+    from_parent.or_else(|| {
+        let trace_id = otel_data
+            .builder
+            .trace_id
+            .filter(|id| *id != TraceId::INVALID)?;
+        let span_id = otel_data
+            .builder
+            .span_id
+            .filter(|id| *id != SpanId::INVALID)?;
+        Some(TraceInfo {
+            trace_id: trace_id.to_string(),
+            span_id: span_id.to_string(),
+        })
     })
 }
+
+// The trace flags of the trace the span belongs to: the parent's, or for a locally rooted
+// span its own sampling decision, which is `None` as long as it wasn't taken yet
+pub(crate) fn trace_flags_from_otel_data(otel_data: &OtelData) -> Option<TraceFlags> {
+    let parent = otel_data.parent_cx.span().span_context().clone();
+    if parent.is_valid() {
+        return Some(parent.trace_flags());
+    }
+
+    let sampling_result = otel_data.builder.sampling_result.as_ref()?;
+    Some(match sampling_result.decision {
+        SamplingDecision::RecordAndSample => TraceFlags::SAMPLED,
+        SamplingDecision::RecordOnly | SamplingDecision::Drop => TraceFlags::default(),
+    })
+}
+
+// An invalid builder span id next to a valid context one hints at a misconfigured stack, e.g.
+// two OpenTelemetry layers fighting over the same `OtelData`. The context id is used then,
+// and the misconfiguration is reported once through the global OpenTelemetry error handler.
+fn report_conflicting_span_ids(context_span_id: &str) {
+    static REPORTED: Once = Once::new();
+    REPORTED.call_once(|| {
+        opentelemetry::global::handle_error(TraceError::from(format!(
+            "span builder holds an invalid span id, using the context's span id {context_span_id} \
+             instead. Is more than one OpenTelemetry layer installed?"
+        )));
+    });
+}
+
+// The span id of the upstream span a span continues from, if its parent is a remote one
+pub(crate) fn remote_parent_span_id(otel_data: &OtelData) -> Option<String> {
+    let parent_cx = otel_data.parent_cx.span();
+    let parent = parent_cx.span_context();
+    (parent.is_valid() && parent.is_remote()).then(|| parent.span_id().to_string())
+}
+
+// Collect the trace ids of all span links which point into a different trace. The span's trace
+// is the one of its parent context if valid: `tracing_opentelemetry` assigns a random builder
+// trace id to every span created without a parent, which `set_parent` doesn't update.
+pub(crate) fn linked_trace_ids(otel_data: &OtelData) -> Vec<String> {
+    let parent_cx = otel_data.parent_cx.span();
+    let parent = parent_cx.span_context();
+    let trace_id = if parent.is_valid() {
+        parent.trace_id()
+    } else {
+        otel_data.builder.trace_id.unwrap_or(TraceId::INVALID)
+    };
+
+    let mut linked_trace_ids: Vec<String> = Vec::new();
+    for link in otel_data.builder.links.iter().flatten() {
+        let linked_trace_id = link.span_context.trace_id();
+        if linked_trace_id == trace_id || linked_trace_id == TraceId::INVALID {
+            continue;
+        }
+
+        let linked_trace_id = linked_trace_id.to_string();
+        if !linked_trace_ids.contains(&linked_trace_id) {
+            linked_trace_ids.push(linked_trace_id);
+        }
+    }
+    linked_trace_ids
+}
+
+// Compute the `traceparent` to forward downstream, continuing the inbound trace with a freshly
+// generated span id, without the overhead of creating an actual span
+pub fn next_hop_traceparent(inbound: &RemoteTraceContext) -> Result<String, TraceParseError> {
+    let span_context = inbound.span_context()?;
+    Ok(format_traceparent(
+        span_context.trace_id(),
+        RandomIdGenerator::default().new_span_id(),
+        span_context.trace_flags(),
+    ))
+}
+
+// Format the `traceparent` to send along with outgoing requests made within `span`, `None` if
+// the span isn't part of a valid trace
+pub fn current_traceparent(span: &Span) -> Option<String> {
+    let cx = span.context();
+    let span_ref = cx.span();
+    let span_context = span_ref.span_context();
+
+    span_context.is_valid().then(|| {
+        format_traceparent(
+            span_context.trace_id(),
+            span_context.span_id(),
+            span_context.trace_flags(),
+        )
+    })
+}
+
+// Add the `traceparent` (and `tracestate` if set) headers of `span` to an outgoing request,
+// the counterpart of `RemoteTraceContext::from_headers`. Requests made outside of a valid trace
+// are passed through untouched.
+#[cfg(feature = "reqwest")]
+pub fn inject_trace_context(
+    request: reqwest::RequestBuilder,
+    span: &Span,
+) -> reqwest::RequestBuilder {
+    let Some(traceparent) = current_traceparent(span) else {
+        return request;
+    };
+    let request = request.header("traceparent", traceparent);
+
+    let trace_state = span.context().span().span_context().trace_state().header();
+    if trace_state.is_empty() {
+        request
+    } else {
+        request.header("tracestate", trace_state)
+    }
+}
+
+// Format the current span's context as a sqlcommenter comment like
+// `/*traceparent='00-..-01'*/` to append to SQL queries, so the database's query logs can be
+// correlated with the trace. `tracestate` is included if set. `None` outside of a valid trace.
+pub fn sql_comment_for_current_span() -> Option<String> {
+    let span = Span::current();
+    let traceparent = current_traceparent(&span)?;
+    let trace_state = span.context().span().span_context().trace_state().header();
+
+    // sqlcommenter wants the keys sorted and the values URL-encoded
+    let mut comment = format!("/*traceparent='{}'", sql_comment_encode(&traceparent));
+    if !trace_state.is_empty() {
+        comment.push_str(&format!(
+            ",tracestate='{}'",
+            sql_comment_encode(&trace_state)
+        ));
+    }
+    comment.push_str("*/");
+    Some(comment)
+}
+
+fn sql_comment_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                char::from(b).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+// Hand the current span's context down to a child process through the `TRACEPARENT` and
+// `TRACESTATE` env variables, the counterpart of `RemoteTraceContext::from_env`. Values the
+// child would inherit from this process are removed when there's nothing to replace them with.
+// Returns whether there was a valid context to propagate.
+pub fn command_with_trace_context(cmd: &mut std::process::Command) -> bool {
+    let cx = Span::current().context();
+    let span_ref = cx.span();
+    let span_context = span_ref.span_context();
+    if !span_context.is_valid() {
+        cmd.env_remove("TRACEPARENT").env_remove("TRACESTATE");
+        return false;
+    }
+
+    cmd.env(
+        "TRACEPARENT",
+        format_traceparent(
+            span_context.trace_id(),
+            span_context.span_id(),
+            span_context.trace_flags(),
+        ),
+    );
+    let trace_state = span_context.trace_state().header();
+    match trace_state.is_empty() {
+        true => cmd.env_remove("TRACESTATE"),
+        false => cmd.env("TRACESTATE", trace_state),
+    };
+    true
+}
+
+// Carry the current span, OpenTelemetry context and subscriber into `f`, so work offloaded to
+// a thread pool (e.g. `rayon::spawn` or `std::thread::spawn`) stays part of the trace
+pub fn with_current_context<T>(f: impl FnOnce() -> T + Send) -> impl FnOnce() -> T + Send {
+    let span = Span::current();
+    let cx = opentelemetry::Context::current();
+    let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+
+    move || {
+        let _guard = cx.attach();
+        tracing::dispatcher::with_default(&dispatch, || span.in_scope(f))
+    }
+}
+
+pub(crate) fn format_traceparent(
+    trace_id: impl fmt::Display,
+    span_id: impl fmt::Display,
+    trace_flags: TraceFlags,
+) -> String {
+    format!("00-{trace_id}-{span_id}-{:02x}", trace_flags.to_u8())
+}
+
+// Convert a 32 hex char trace id into X-Ray's `1-{timestamp}-{random}` root id form, the
+// first 8 hex chars are reinterpreted as the epoch timestamp segment
+pub fn xray_trace_id(trace_id: &str) -> Option<String> {
+    let trace_id = trace_id.to_ascii_lowercase();
+    if !is_lower_hex(&trace_id, 32) {
+        return None;
+    }
+
+    let (timestamp, random) = trace_id.split_at(8);
+    Some(format!("1-{timestamp}-{random}"))
+}
+
+// Parse an inbound propagation header into a `RemoteTraceContext`, dispatching on the header
+// name. The value is untrusted network input, so malformed values and unknown headers yield
+// `None` and this must never panic.
+pub fn parse_trace_header(name: &str, value: &str) -> Option<RemoteTraceContext> {
+    match name.to_ascii_lowercase().as_str() {
+        "traceparent" => parse_traceparent(value),
+        "x-amzn-trace-id" => RemoteTraceContext::from_xray(value),
+        "b3" => RemoteTraceContext::from_b3_single(value),
+        "uber-trace-id" => RemoteTraceContext::from_uber(value),
+        "x-cloud-trace-context" => RemoteTraceContext::from_cloud_trace_context(value),
+        _ => None,
+    }
+}
+
+/// The propagation format `parse_context` parses a value as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat<'a> {
+    /// W3C `traceparent`.
+    W3c,
+    /// The single `b3` header.
+    B3Single,
+    /// The multiple B3 headers, the value is the one of `X-B3-TraceId`.
+    B3Multi {
+        span_id: &'a str,
+        sampled: Option<&'a str>,
+    },
+    /// Jaeger's `uber-trace-id`.
+    Uber,
+    /// AWS `X-Amzn-Trace-Id`.
+    XRay,
+    /// Google Cloud's `X-Cloud-Trace-Context`.
+    Gcp,
+}
+
+impl TraceFormat<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::W3c => "traceparent",
+            Self::B3Single => "b3",
+            Self::B3Multi { .. } => "X-B3-TraceId",
+            Self::Uber => "uber-trace-id",
+            Self::XRay => "X-Amzn-Trace-Id",
+            Self::Gcp => "X-Cloud-Trace-Context",
+        }
+    }
+}
+
+/// How closely `parse_context` expects a value to follow its format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseStrictness {
+    /// Surrounding whitespace, uppercase hex and ids shortened by leading zeros are accepted.
+    #[default]
+    Lenient,
+    /// The value has to be exactly as specified by its format.
+    Strict,
+}
+
+// Parse `value` as the given `format`. Unlike `parse_trace_header`, the format isn't derived from
+// a header name, and the error tells why a value was rejected. The result is always `validate`d.
+pub fn parse_context(
+    format: TraceFormat<'_>,
+    value: &str,
+    strictness: ParseStrictness,
+) -> Result<RemoteTraceContext, TraceParseError> {
+    let strict = strictness == ParseStrictness::Strict;
+    let malformed = || TraceParseError::Malformed {
+        format: format.name(),
+    };
+    if strict && value.trim() != value {
+        return Err(malformed());
+    }
+
+    let trace_context = match format {
+        TraceFormat::W3c if strict => RemoteTraceContext::from_traceparent(value)?,
+        TraceFormat::W3c => RemoteTraceContext::from_traceparent(&value.to_ascii_lowercase())?,
+        TraceFormat::B3Single => RemoteTraceContext::from_b3_single(value).ok_or_else(malformed)?,
+        TraceFormat::B3Multi { span_id, sampled } => {
+            RemoteTraceContext::from_b3(value, span_id, sampled).ok_or_else(malformed)?
+        }
+        TraceFormat::Uber => RemoteTraceContext::from_uber(value).ok_or_else(malformed)?,
+        TraceFormat::XRay => RemoteTraceContext::from_xray(value).ok_or_else(malformed)?,
+        TraceFormat::Gcp => {
+            RemoteTraceContext::from_cloud_trace_context(value).ok_or_else(malformed)?
+        }
+    };
+
+    if strict {
+        check_strict_ids(&format, value)?;
+    }
+
+    trace_context.validate()?;
+    Ok(trace_context)
+}
+
+// Check the ids as written in `value` against the grammar of `format`, since the parsers
+// lowercase them and left-pad shortened ones
+fn check_strict_ids(format: &TraceFormat<'_>, value: &str) -> Result<(), TraceParseError> {
+    let lower_hex = |id: &str| id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    let (trace_id, trace_id_valid, span_id, span_id_valid) = match *format {
+        // `from_traceparent` already requires the exact 32/16 lowercase hex chars
+        TraceFormat::W3c => return Ok(()),
+        TraceFormat::B3Single | TraceFormat::B3Multi { .. } => {
+            let (trace_id, span_id) = match *format {
+                TraceFormat::B3Multi { span_id, .. } => (value, span_id),
+                _ => {
+                    let mut parts = value.split('-');
+                    (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+                }
+            };
+            // 64 or 128 bit trace ids
+            let trace_id_valid = matches!(trace_id.len(), 16 | 32) && lower_hex(trace_id);
+            (
+                trace_id,
+                trace_id_valid,
+                span_id,
+                span_id.len() == 16 && lower_hex(span_id),
+            )
+        }
+        TraceFormat::Uber => {
+            let mut parts = value.split(':');
+            let (trace_id, span_id) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+            // leading zeros may be dropped
+            let trace_id_valid = (1..=32).contains(&trace_id.len()) && lower_hex(trace_id);
+            let span_id_valid = (1..=16).contains(&span_id.len()) && lower_hex(span_id);
+            (trace_id, trace_id_valid, span_id, span_id_valid)
+        }
+        TraceFormat::XRay => {
+            let segment = |key: &str| {
+                value
+                    .split(';')
+                    .find_map(|part| part.split_once('=').filter(|(k, _)| *k == key))
+                    .map_or("", |(_, value)| value)
+            };
+            let (root, parent) = (segment("Root"), segment("Parent"));
+            let root_valid = root.len() == 35
+                && root.is_ascii()
+                && root.starts_with("1-")
+                && root.as_bytes()[10] == b'-'
+                && lower_hex(&root[2..10])
+                && lower_hex(&root[11..]);
+            (
+                root,
+                root_valid,
+                parent,
+                parent.len() == 16 && lower_hex(parent),
+            )
+        }
+        TraceFormat::Gcp => {
+            let ids = value.split_once(';').map_or(value, |(ids, _)| ids);
+            let (trace_id, span_id) = ids.split_once('/').unwrap_or((ids, ""));
+            // the span id is decimal
+            let span_id_valid = !span_id.is_empty() && span_id.bytes().all(|b| b.is_ascii_digit());
+            (
+                trace_id,
+                trace_id.len() == 32 && lower_hex(trace_id),
+                span_id,
+                span_id_valid,
+            )
+        }
+    };
+
+    let invalid = |field, value: &str| TraceParseError::InvalidField {
+        field,
+        value: value.to_owned(),
+    };
+    if !trace_id_valid {
+        return Err(invalid("trace_id", trace_id));
+    }
+    if !span_id_valid {
+        return Err(invalid("span_id", span_id));
+    }
+    Ok(())
+}
+
+fn parse_traceparent(value: &str) -> Option<RemoteTraceContext> {
+    RemoteTraceContext::from_traceparent(value).ok()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceParseError {
+    /// An id of a `RemoteTraceContext` couldn't be decoded as hex.
+    InvalidHex {
+        field: &'static str,
+        source: ParseIntError,
+    },
+    /// The value doesn't consist of the `-` separated version, trace id, span id and flags.
+    MalformedHeader,
+    UnsupportedVersion(String),
+    /// A field has the wrong length, isn't lowercase hex or is all zeros.
+    InvalidField {
+        field: &'static str,
+        value: String,
+    },
+    /// The span already has a remote parent attached, see `RemoteSpanOptions::strict`.
+    AlreadyParented,
+    /// The value doesn't have the shape of the `format` passed to `parse_context`.
+    Malformed {
+        format: &'static str,
+    },
+    /// The context passed to `remote_trace_span_from_context` holds no valid remote span.
+    NoRemoteContext,
+}
+
+impl fmt::Display for TraceParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedHeader => {
+                f.write_str("traceparent must be `{version}-{trace_id}-{span_id}-{trace_flags}`")
+            }
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported traceparent version {version:?}")
+            }
+            Self::InvalidField { field, value } => write!(f, "invalid {field} {value:?}"),
+            Self::InvalidHex { field, .. } => write!(f, "{field} is not a valid hex id"),
+            Self::AlreadyParented => f.write_str("span already has a remote parent"),
+            Self::Malformed { format } => write!(f, "malformed {format} value"),
+            Self::NoRemoteContext => f.write_str("context holds no valid remote span"),
+        }
+    }
+}
+
+impl Error for TraceParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidHex { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn is_lower_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+// Wrap `error` together with the trace id of the current span, so the error still points to
+// the trace it originated from when it is logged further up the stack
+pub fn attach_trace_id<E>(error: E) -> TracedError<E> {
+    let span_context = Span::current().context().span().span_context().clone();
+    let trace_id = span_context
+        .is_valid()
+        .then(|| span_context.trace_id().to_string());
+
+    TracedError { error, trace_id }
+}
+
+#[derive(Debug)]
+pub struct TracedError<E> {
+    error: E,
+    trace_id: Option<String>,
+}
+
+impl<E> TracedError<E> {
+    pub fn trace_id(&self) -> Option<&str> {
+        self.trace_id.as_deref()
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.error
+    }
+
+    pub fn into_inner(self) -> E {
+        self.error
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for TracedError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.trace_id {
+            Some(trace_id) => write!(f, "{} (trace_id: {trace_id})", self.error),
+            None => self.error.fmt(f),
+        }
+    }
+}
+
+// The wrapped error is already part of the `Display` output, so skip it in the source chain
+impl<E: Error> Error for TracedError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::{sdk::trace::TracerProvider, trace::TracerProvider as _};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    fn remote_context() -> RemoteTraceContext {
+        RemoteTraceContext {
+            info: TraceInfo {
+                trace_id: TRACE_ID.into(),
+                span_id: SPAN_ID.into(),
+            },
+            trace_flags: TraceFlags::SAMPLED,
+            ..Default::default()
+        }
+    }
+
+    // Run `f` on a subscriber with an OpenTelemetry layer, the provider has no exporters
+    fn with_otel<R>(f: impl FnOnce() -> R) -> R {
+        let provider = TracerProvider::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, f)
+    }
+
+    fn otel_data<R>(span: &Span, f: impl FnOnce(&OtelData) -> R) -> R {
+        span.with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>().expect("registry");
+            let span = registry.span(id).expect("span");
+            let extensions = span.extensions();
+            f(extensions.get::<OtelData>().expect("otel data"))
+        })
+        .expect("span is enabled")
+    }
+
+    fn span_context(trace_id: &str, span_id: &str) -> SpanContext {
+        SpanContext::new(
+            TraceId::from_hex(trace_id).unwrap(),
+            SpanId::from_hex(span_id).unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        )
+    }
+
+    #[test]
+    fn links_into_the_remote_trace_are_not_cross_trace() {
+        with_otel(|| {
+            let span = remote_trace_span(tracing::info_span!("test"), &remote_context()).unwrap();
+            span.add_link(span_context(TRACE_ID, "1111111111111111"));
+            assert!(otel_data(&span, linked_trace_ids).is_empty());
+        });
+    }
+
+    #[test]
+    fn links_into_other_traces_are_listed_once() {
+        with_otel(|| {
+            let other = "0af7651916cd43dd8448eb211c80319c";
+            let span = remote_trace_span(tracing::info_span!("test"), &remote_context()).unwrap();
+            span.add_link(span_context(other, "1111111111111111"));
+            span.add_link(span_context(other, "2222222222222222"));
+            assert_eq!(otel_data(&span, linked_trace_ids), vec![other.to_owned()]);
+        });
+    }
+
+    #[test]
+    fn links_of_root_spans_compare_against_their_own_trace() {
+        with_otel(|| {
+            let span = tracing::info_span!("test");
+            let trace_id = otel_data(&span, |data| data.builder.trace_id.unwrap().to_string());
+            span.add_link(span_context(&trace_id, "1111111111111111"));
+            assert!(otel_data(&span, linked_trace_ids).is_empty());
+        });
+    }
+
+    fn command_envs(cmd: &std::process::Command) -> HashMap<String, Option<String>> {
+        cmd.get_envs()
+            .map(|(key, value)| {
+                let value = value.map(|value| value.to_string_lossy().into_owned());
+                (key.to_string_lossy().into_owned(), value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn commands_get_the_current_context() {
+        with_otel(|| {
+            let remote = RemoteTraceContext {
+                trace_state: Some("vendor=value".into()),
+                ..remote_context()
+            };
+            let span = remote_trace_span(tracing::info_span!("test"), &remote).unwrap();
+            let mut cmd = std::process::Command::new("true");
+            assert!(span.in_scope(|| command_with_trace_context(&mut cmd)));
+
+            let envs = command_envs(&cmd);
+            let traceparent = envs["TRACEPARENT"].as_deref().unwrap();
+            assert!(traceparent.starts_with(&format!("00-{TRACE_ID}-")));
+            assert!(traceparent.ends_with("-01"));
+            assert_eq!(envs["TRACESTATE"].as_deref(), Some("vendor=value"));
+        });
+    }
+
+    #[test]
+    fn commands_do_not_inherit_a_stale_trace_state() {
+        with_otel(|| {
+            let span = remote_trace_span(tracing::info_span!("test"), &remote_context()).unwrap();
+            let mut cmd = std::process::Command::new("true");
+            cmd.env("TRACESTATE", "stale=value");
+            assert!(span.in_scope(|| command_with_trace_context(&mut cmd)));
+
+            let envs = command_envs(&cmd);
+            assert!(envs["TRACEPARENT"].is_some());
+            assert_eq!(envs["TRACESTATE"], None);
+        });
+    }
+
+    #[test]
+    fn commands_outside_of_a_trace_do_not_inherit_a_context() {
+        let mut cmd = std::process::Command::new("true");
+        assert!(!command_with_trace_context(&mut cmd));
+
+        let envs = command_envs(&cmd);
+        assert_eq!(envs["TRACEPARENT"], None);
+        assert_eq!(envs["TRACESTATE"], None);
+    }
+
+    #[test]
+    fn traceparent_is_parsed() {
+        let remote = RemoteTraceContext::from_traceparent(&format!("00-{TRACE_ID}-{SPAN_ID}-01"));
+        assert_eq!(
+            remote.as_ref().map(|remote| &remote.info),
+            Ok(&remote_context().info)
+        );
+        assert_eq!(remote.unwrap().trace_flags, TraceFlags::SAMPLED);
+
+        let unsampled =
+            RemoteTraceContext::from_traceparent(&format!(" 00-{TRACE_ID}-{SPAN_ID}-00 "));
+        assert_eq!(unsampled.unwrap().trace_flags, TraceFlags::default());
+    }
+
+    #[test]
+    fn traceparent_of_future_versions_may_have_more_parts() {
+        let header = format!("01-{TRACE_ID}-{SPAN_ID}-01-future");
+        assert!(RemoteTraceContext::from_traceparent(&header).is_ok());
+
+        let header = format!("00-{TRACE_ID}-{SPAN_ID}-01-future");
+        assert_eq!(
+            RemoteTraceContext::from_traceparent(&header),
+            Err(TraceParseError::MalformedHeader)
+        );
+    }
+
+    #[test]
+    fn traceparent_rejects_invalid_values() {
+        let invalid = |field, value: &str| {
+            Err(TraceParseError::InvalidField {
+                field,
+                value: value.into(),
+            })
+        };
+        let zeros = "0".repeat(32);
+        let upper = TRACE_ID.to_ascii_uppercase();
+        for (header, expected) in [
+            (String::new(), Err(TraceParseError::MalformedHeader)),
+            (
+                format!("00-{TRACE_ID}-{SPAN_ID}"),
+                Err(TraceParseError::MalformedHeader),
+            ),
+            (
+                format!("ff-{TRACE_ID}-{SPAN_ID}-01"),
+                Err(TraceParseError::UnsupportedVersion("ff".into())),
+            ),
+            (
+                format!("0-{TRACE_ID}-{SPAN_ID}-01"),
+                Err(TraceParseError::UnsupportedVersion("0".into())),
+            ),
+            (
+                format!("00-{zeros}-{SPAN_ID}-01"),
+                invalid("trace_id", &zeros),
+            ),
+            (
+                format!("00-{upper}-{SPAN_ID}-01"),
+                invalid("trace_id", &upper),
+            ),
+            (
+                format!("00-{}-{SPAN_ID}-01", &TRACE_ID[1..]),
+                invalid("trace_id", &TRACE_ID[1..]),
+            ),
+            (
+                format!("00-{TRACE_ID}-0000000000000000-01"),
+                invalid("span_id", "0000000000000000"),
+            ),
+            (
+                format!("00-{TRACE_ID}-{SPAN_ID}-1"),
+                invalid("trace_flags", "1"),
+            ),
+            (
+                format!("00-{TRACE_ID}-{SPAN_ID}-0g"),
+                invalid("trace_flags", "0g"),
+            ),
+        ] {
+            assert_eq!(
+                RemoteTraceContext::from_traceparent(&header),
+                expected,
+                "{header}"
+            );
+        }
+    }
+
+    #[test]
+    fn b3_headers_are_parsed() {
+        let remote = RemoteTraceContext::from_b3(TRACE_ID, SPAN_ID, Some("1")).unwrap();
+        assert_eq!(remote, remote_context());
+
+        for (sampled, expected) in [
+            (Some("true"), TraceFlags::SAMPLED),
+            (Some("d"), TraceFlags::SAMPLED),
+            (Some("0"), TraceFlags::default()),
+            (Some("false"), TraceFlags::default()),
+            (None, TraceFlags::default()),
+        ] {
+            let remote = RemoteTraceContext::from_b3(TRACE_ID, SPAN_ID, sampled).unwrap();
+            assert_eq!(remote.trace_flags, expected, "{sampled:?}");
+        }
+        assert_eq!(
+            RemoteTraceContext::from_b3(TRACE_ID, SPAN_ID, Some("yes")),
+            None
+        );
+    }
+
+    #[test]
+    fn b3_trace_ids_of_64_bit_are_padded() {
+        let remote = RemoteTraceContext::from_b3("A3CE929D0E0E4736", SPAN_ID, None).unwrap();
+        assert_eq!(remote.info.trace_id, "0000000000000000a3ce929d0e0e4736");
+    }
+
+    #[test]
+    fn b3_headers_reject_invalid_ids() {
+        for (trace_id, span_id) in [
+            ("", SPAN_ID),
+            (&TRACE_ID[1..], SPAN_ID),
+            ("00000000000000000000000000000000", SPAN_ID),
+            ("0000000000000000", SPAN_ID),
+            (TRACE_ID, "0000000000000000"),
+            (TRACE_ID, &SPAN_ID[1..]),
+            (TRACE_ID, "00f067aa0ba902bx"),
+        ] {
+            assert_eq!(
+                RemoteTraceContext::from_b3(trace_id, span_id, None),
+                None,
+                "{trace_id}-{span_id}"
+            );
+        }
+    }
+
+    #[test]
+    fn b3_single_header_is_parsed() {
+        let remote = RemoteTraceContext::from_b3_single(&format!("{TRACE_ID}-{SPAN_ID}-1"));
+        assert_eq!(remote, Some(remote_context()));
+
+        let with_parent = format!("{TRACE_ID}-{SPAN_ID}-d-1111111111111111");
+        let remote = RemoteTraceContext::from_b3_single(&with_parent).unwrap();
+        assert_eq!(remote.trace_flags, TraceFlags::SAMPLED);
+
+        let remote = RemoteTraceContext::from_b3_single(&format!("{TRACE_ID}-{SPAN_ID}")).unwrap();
+        assert_eq!(remote.trace_flags, TraceFlags::default());
+    }
+
+    #[test]
+    fn b3_single_header_rejects_invalid_values() {
+        for header in [
+            "0".to_owned(),
+            "1".to_owned(),
+            TRACE_ID.to_owned(),
+            format!("{TRACE_ID}-{SPAN_ID}-1-1111111111111111-extra"),
+            format!("{TRACE_ID}-{SPAN_ID}-2"),
+        ] {
+            assert_eq!(
+                RemoteTraceContext::from_b3_single(&header),
+                None,
+                "{header}"
+            );
+        }
+    }
+
+    #[test]
+    fn uber_header_is_parsed() {
+        let remote = RemoteTraceContext::from_uber(&format!("{TRACE_ID}:{SPAN_ID}:0:1"));
+        assert_eq!(remote, Some(remote_context()));
+
+        // only the lowest bit is the sampling decision, the debug bit is dropped
+        let remote = RemoteTraceContext::from_uber(&format!("{TRACE_ID}:{SPAN_ID}:0:2")).unwrap();
+        assert_eq!(remote.trace_flags, TraceFlags::default());
+        let remote = RemoteTraceContext::from_uber(&format!("{TRACE_ID}:{SPAN_ID}:0:3")).unwrap();
+        assert_eq!(remote.trace_flags, TraceFlags::SAMPLED);
+    }
+
+    #[test]
+    fn uber_header_ids_are_padded() {
+        let remote = RemoteTraceContext::from_uber("A3CE929D0E0E4736:F067AA0BA902B7:0:1").unwrap();
+        assert_eq!(remote.info.trace_id, "0000000000000000a3ce929d0e0e4736");
+        assert_eq!(remote.info.span_id, SPAN_ID);
+    }
+
+    #[test]
+    fn uber_header_rejects_invalid_values() {
+        for header in [
+            String::new(),
+            format!("{TRACE_ID}:{SPAN_ID}:0"),
+            format!("{TRACE_ID}:{SPAN_ID}:0:1:extra"),
+            format!("0{TRACE_ID}:{SPAN_ID}:0:1"),
+            format!("{TRACE_ID}:0{SPAN_ID}:0:1"),
+            format!("0:{SPAN_ID}:0:1"),
+            format!("{TRACE_ID}:0:0:1"),
+            format!("{TRACE_ID}:{SPAN_ID}:0:x"),
+            format!("{TRACE_ID}:00f067aa0ba902bx:0:1"),
+        ] {
+            assert_eq!(RemoteTraceContext::from_uber(&header), None, "{header}");
+        }
+    }
+
+    #[test]
+    fn cloud_trace_context_is_parsed() {
+        let span_id = u64::from_str_radix(SPAN_ID, 16).unwrap();
+        let remote =
+            RemoteTraceContext::from_cloud_trace_context(&format!("{TRACE_ID}/{span_id};o=1"));
+        assert_eq!(remote, Some(remote_context()));
+
+        for header in [
+            format!("{TRACE_ID}/{span_id};o=0"),
+            format!("{TRACE_ID}/{span_id}"),
+        ] {
+            let remote = RemoteTraceContext::from_cloud_trace_context(&header).unwrap();
+            assert_eq!(remote.trace_flags, TraceFlags::default(), "{header}");
+        }
+    }
+
+    #[test]
+    fn cloud_trace_context_rejects_invalid_values() {
+        for header in [
+            String::new(),
+            TRACE_ID.to_owned(),
+            format!("{TRACE_ID}/0"),
+            format!("{TRACE_ID}/{SPAN_ID}"),
+            format!("{TRACE_ID}/1;o=2"),
+            format!("{}/1", "0".repeat(32)),
+            format!("{}/1", &TRACE_ID[1..]),
+        ] {
+            assert_eq!(
+                RemoteTraceContext::from_cloud_trace_context(&header),
+                None,
+                "{header}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_context_dispatches_on_the_format() {
+        let span_id = u64::from_str_radix(SPAN_ID, 16).unwrap();
+        let xray = format!(
+            "Root=1-{}-{};Parent={SPAN_ID};Sampled=1",
+            &TRACE_ID[..8],
+            &TRACE_ID[8..]
+        );
+        for (format, value) in [
+            (TraceFormat::W3c, format!("00-{TRACE_ID}-{SPAN_ID}-01")),
+            (TraceFormat::B3Single, format!("{TRACE_ID}-{SPAN_ID}-1")),
+            (
+                TraceFormat::B3Multi {
+                    span_id: SPAN_ID,
+                    sampled: Some("1"),
+                },
+                TRACE_ID.to_owned(),
+            ),
+            (TraceFormat::Uber, format!("{TRACE_ID}:{SPAN_ID}:0:1")),
+            (TraceFormat::XRay, xray),
+            (TraceFormat::Gcp, format!("{TRACE_ID}/{span_id};o=1")),
+        ] {
+            for strictness in [ParseStrictness::Lenient, ParseStrictness::Strict] {
+                assert_eq!(
+                    parse_context(format, &value, strictness),
+                    Ok(remote_context()),
+                    "{format:?} {strictness:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn parse_context_tells_why_a_value_was_rejected() {
+        assert_eq!(
+            parse_context(TraceFormat::Uber, "garbage", ParseStrictness::Lenient),
+            Err(TraceParseError::Malformed {
+                format: "uber-trace-id"
+            })
+        );
+        assert_eq!(
+            parse_context(TraceFormat::W3c, "00-garbage", ParseStrictness::Lenient),
+            Err(TraceParseError::MalformedHeader)
+        );
+    }
+
+    #[test]
+    fn parse_context_strictly_requires_the_exact_format() {
+        let upper = TRACE_ID.to_ascii_uppercase();
+        for (format, value) in [
+            (TraceFormat::W3c, format!("00-{upper}-{SPAN_ID}-01")),
+            (TraceFormat::B3Single, format!("{upper}-{SPAN_ID}-1")),
+            (TraceFormat::Uber, format!("{upper}:{SPAN_ID}:0:1")),
+            (
+                TraceFormat::XRay,
+                format!("Root=1-{}-{};Parent={SPAN_ID}", &upper[..8], &upper[8..]),
+            ),
+            (TraceFormat::Gcp, format!("{upper}/1")),
+        ] {
+            assert!(
+                parse_context(format, &value, ParseStrictness::Lenient).is_ok(),
+                "{format:?}"
+            );
+            assert!(
+                matches!(
+                    parse_context(format, &value, ParseStrictness::Strict),
+                    Err(TraceParseError::InvalidField {
+                        field: "trace_id",
+                        ..
+                    })
+                ),
+                "{format:?}"
+            );
+        }
+
+        let padded = format!(" 00-{TRACE_ID}-{SPAN_ID}-01 ");
+        assert!(parse_context(TraceFormat::W3c, &padded, ParseStrictness::Lenient).is_ok());
+        assert_eq!(
+            parse_context(TraceFormat::W3c, &padded, ParseStrictness::Strict),
+            Err(TraceParseError::Malformed {
+                format: "traceparent"
+            })
+        );
+    }
+
+    fn xray_root() -> String {
+        format!("1-{}-{}", &TRACE_ID[..8], &TRACE_ID[8..])
+    }
+
+    #[test]
+    fn xray_header_is_parsed() {
+        let root = xray_root();
+        let header = format!("Root={root};Parent={SPAN_ID};Sampled=1");
+        assert_eq!(
+            RemoteTraceContext::from_xray(&header),
+            Some(remote_context())
+        );
+
+        // segments may come in any order, with whitespace, uppercase hex and unknown keys
+        let header = format!(
+            "Self=1-67891234-abcdef; Sampled=0 ;Parent={};Root={};",
+            SPAN_ID.to_ascii_uppercase(),
+            root.to_ascii_uppercase()
+        );
+        let remote = RemoteTraceContext::from_xray(&header).unwrap();
+        assert_eq!(remote.info, remote_context().info);
+        assert_eq!(remote.trace_flags, TraceFlags::default());
+    }
+
+    #[test]
+    fn xray_header_round_trips_the_root_id() {
+        let root = xray_trace_id(TRACE_ID).unwrap();
+        assert_eq!(root, xray_root());
+        let remote = RemoteTraceContext::from_xray(&format!("Root={root};Parent={SPAN_ID}"));
+        assert_eq!(remote.unwrap().info.trace_id, TRACE_ID);
+    }
+
+    #[test]
+    fn xray_header_rejects_invalid_values() {
+        let root = xray_root();
+        for header in [
+            String::new(),
+            format!("Root={root}"),
+            format!("Parent={SPAN_ID}"),
+            format!("Root={root};Parent=1"),
+            format!("Root=2{};Parent={SPAN_ID}", &root[1..]),
+            format!("Root={root}-1;Parent={SPAN_ID}"),
+            format!("Root={};Parent={SPAN_ID}", &root[..34]),
+            format!("Root=1-{TRACE_ID};Parent={SPAN_ID}"),
+        ] {
+            assert_eq!(RemoteTraceContext::from_xray(&header), None, "{header}");
+        }
+    }
+
+    // The env is process wide, so this is the only test touching `TRACEPARENT`/`TRACESTATE`
+    #[test]
+    fn context_is_read_from_the_env() {
+        std::env::remove_var("TRACEPARENT");
+        std::env::set_var("TRACESTATE", "vendor=value");
+        assert_eq!(RemoteTraceContext::from_env(), None);
+
+        std::env::set_var("TRACEPARENT", "00-garbage");
+        assert_eq!(RemoteTraceContext::from_env(), None);
+
+        std::env::set_var("TRACEPARENT", format!("00-{TRACE_ID}-{SPAN_ID}-01"));
+        let expected = RemoteTraceContext {
+            trace_state: Some("vendor=value".into()),
+            ..remote_context()
+        };
+        assert_eq!(RemoteTraceContext::from_env(), Some(expected));
+
+        std::env::remove_var("TRACESTATE");
+        assert_eq!(RemoteTraceContext::from_env(), Some(remote_context()));
+        std::env::remove_var("TRACEPARENT");
+    }
+
+    const HEADER_NAMES: [&str; 5] = [
+        "traceparent",
+        "x-amzn-trace-id",
+        "b3",
+        "uber-trace-id",
+        "x-cloud-trace-context",
+    ];
+
+    #[test]
+    fn trace_headers_are_dispatched_by_name() {
+        let span_id = u64::from_str_radix(SPAN_ID, 16).unwrap();
+        for (name, value) in [
+            ("traceparent", format!("00-{TRACE_ID}-{SPAN_ID}-01")),
+            (
+                "X-Amzn-Trace-Id",
+                format!("Root={};Parent={SPAN_ID};Sampled=1", xray_root()),
+            ),
+            ("b3", format!("{TRACE_ID}-{SPAN_ID}-1")),
+            ("Uber-Trace-Id", format!("{TRACE_ID}:{SPAN_ID}:0:1")),
+            ("X-Cloud-Trace-Context", format!("{TRACE_ID}/{span_id};o=1")),
+        ] {
+            assert_eq!(
+                parse_trace_header(name, &value),
+                Some(remote_context()),
+                "{name}"
+            );
+        }
+        let traceparent = format!("00-{TRACE_ID}-{SPAN_ID}-01");
+        assert_eq!(parse_trace_header("x-unknown", &traceparent), None);
+    }
+
+    // Deterministic xorshift, so a failing property reproduces on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn hex(&mut self, len: usize) -> String {
+            (0..len)
+                .map(|_| char::from(b"0123456789abcdef"[self.below(16)]))
+                .collect()
+        }
+
+        // Bytes biased towards the delimiters and hex digits of the formats, so the parsers get
+        // past their first checks
+        fn garbage(&mut self) -> String {
+            const ALPHABET: &[u8] = b"0123456789abcdefABCDEF-:;/= \t\nRootParentSampledo\xff\x00";
+            let bytes: Vec<u8> = (0..self.below(80))
+                .map(|_| match self.below(4) {
+                    0 => self.next() as u8,
+                    _ => ALPHABET[self.below(ALPHABET.len())],
+                })
+                .collect();
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+    }
+
+    #[test]
+    fn arbitrary_header_values_never_panic() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20_000 {
+            let value = rng.garbage();
+            for name in HEADER_NAMES {
+                if let Some(remote) = parse_trace_header(name, &value) {
+                    assert_eq!(remote.validate(), Ok(()), "{name}: {value:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mutated_header_values_never_panic() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..5_000 {
+            let (trace_id, span_id) = (rng.hex(32), rng.hex(16));
+            let values = [
+                format!("00-{trace_id}-{span_id}-01"),
+                format!(
+                    "Root=1-{}-{};Parent={span_id}",
+                    &trace_id[..8],
+                    &trace_id[8..]
+                ),
+                format!("{trace_id}-{span_id}-1"),
+                format!("{trace_id}:{span_id}:0:1"),
+                format!("{trace_id}/{};o=1", rng.next()),
+            ];
+            for (name, value) in HEADER_NAMES.into_iter().zip(values) {
+                let mut value = value.into_bytes();
+                for _ in 0..=rng.below(3) {
+                    match rng.below(3) {
+                        0 => value.truncate(rng.below(value.len() + 1)),
+                        1 if !value.is_empty() => {
+                            let at = rng.below(value.len());
+                            value[at] = rng.next() as u8;
+                        }
+                        _ => value.insert(rng.below(value.len() + 1), rng.next() as u8),
+                    }
+                }
+                let value = String::from_utf8_lossy(&value);
+                if let Some(remote) = parse_trace_header(name, &value) {
+                    assert_eq!(remote.validate(), Ok(()), "{name}: {value:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn formatted_traceparents_parse_back() {
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+        for _ in 0..5_000 {
+            let info = TraceInfo {
+                trace_id: rng.hex(32),
+                span_id: rng.hex(16),
+            };
+            let flags = TraceFlags::new(rng.next() as u8);
+            let parsed = parse_trace_header("traceparent", &info.to_traceparent(flags));
+            // all zero ids are invalid, which random ids practically never are
+            let parsed = parsed.expect("valid traceparent");
+            assert_eq!((parsed.info, parsed.trace_flags), (info, flags));
+        }
+    }
+}